@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+
+use crate::{texture::UNITS, Orientation};
+
+/// Surface a decal is stuck to. Cells of the upper storey come after the ground floor's
+/// like in the lighting
+#[derive(Copy, Clone)]
+pub enum Surface {
+    Floor(usize),
+    Wall(usize, Orientation),
+}
+
+/// Texture drawn over part of a wall face or floor cell, like a blood splat or a poster
+#[derive(Copy, Clone)]
+pub struct Decal {
+    pub texture: usize,
+    /// Middle of the decal on the surface's texture, in `UNITS`
+    pub center: [f32; 2],
+    /// Width and height in `UNITS`
+    pub size: f32,
+    /// Added while playing rather than placed in the level
+    runtime: bool,
+}
+
+impl Decal {
+    pub fn new(texture: usize, center: [f32; 2], size: f32) -> Self {
+        Self {
+            texture,
+            center,
+            size,
+            runtime: false,
+        }
+    }
+
+    /// Position in the decal's texture of a point of the surface, `None` outside of it
+    #[inline(always)]
+    pub fn at(&self, position: [f32; 2]) -> Option<[f32; 2]> {
+        let units = UNITS as f32;
+        let scale = units / self.size;
+        let x = (position[0] - self.center[0]) * scale + units * 0.5;
+        let y = (position[1] - self.center[1]) * scale + units * 0.5;
+        ((0.0..units).contains(&x) && (0.0..units).contains(&y)).then_some([x, y])
+    }
+}
+
+/// Decals of every surface of the map. Only so many runtime decals are kept, the oldest
+/// ones go first
+pub struct Decals {
+    surfaces: Vec<Vec<Decal>>,
+    /// Surfaces the runtime decals were added to, oldest first
+    runtime: VecDeque<usize>,
+    limit: usize,
+    cells: usize,
+}
+
+impl Decals {
+    pub fn new(map_size: (usize, usize), limit: usize) -> Self {
+        let cells = map_size.0 * map_size.1;
+        Self {
+            // Floors of the ground storey, then the four faces of each cell of both storeys
+            surfaces: vec![vec![]; cells * 9],
+            runtime: VecDeque::new(),
+            limit,
+            cells,
+        }
+    }
+
+    fn index(&self, surface: Surface) -> usize {
+        match surface {
+            Surface::Floor(cell) => cell,
+            Surface::Wall(cell, orientation) => self.cells + cell * 4 + orientation as usize - 1,
+        }
+    }
+
+    /// Decal that stays for the whole level
+    pub fn place(&mut self, surface: Surface, decal: Decal) {
+        let index = self.index(surface);
+        self.surfaces[index].push(decal);
+    }
+
+    /// Decal added while playing, dropping the oldest one over the limit
+    pub fn add(&mut self, surface: Surface, decal: Decal) {
+        let index = self.index(surface);
+        self.surfaces[index].push(Decal {
+            runtime: true,
+            ..decal
+        });
+        self.runtime.push_back(index);
+        if self.runtime.len() > self.limit {
+            if let Some(oldest) = self.runtime.pop_front() {
+                // Runtime decals of a surface are in the order they were added too
+                let decals = &mut self.surfaces[oldest];
+                if let Some(i) = decals.iter().position(|decal| decal.runtime) {
+                    decals.remove(i);
+                }
+            }
+        }
+    }
+
+    /// Decals of a surface, floors of the upper storey have none
+    #[inline(always)]
+    pub fn on(&self, surface: Surface) -> &[Decal] {
+        match surface {
+            Surface::Floor(cell) if cell >= self.cells => &[],
+            _ => &self.surfaces[self.index(surface)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn oldest_runtime_decals_go_first() {
+        let mut decals = Decals::new((2, 2), 2);
+        let wall = Surface::Wall(1, Orientation::E);
+        decals.place(wall, Decal::new(0, [64.0, 64.0], 32.0));
+        decals.add(wall, Decal::new(1, [64.0, 64.0], 32.0));
+        decals.add(Surface::Floor(3), Decal::new(2, [64.0, 64.0], 32.0));
+        decals.add(wall, Decal::new(3, [64.0, 64.0], 32.0));
+        let textures: Vec<usize> = decals.on(wall).iter().map(|d| d.texture).collect();
+        assert_eq!(textures, [0, 3]);
+        assert_eq!(decals.on(Surface::Floor(3)).len(), 1);
+        assert!(decals.on(Surface::Wall(1, Orientation::W)).is_empty());
+        assert!(decals.on(Surface::Floor(7)).is_empty());
+
+        let decal = decals.on(wall)[0];
+        assert_eq!(decal.at([64.0, 64.0]), Some([64.0, 64.0]));
+        assert_eq!(decal.at([48.0, 79.0]), Some([0.0, 124.0]));
+        assert!(decal.at([48.0, 80.0]).is_none());
+        assert!(decal.at([47.0, 64.0]).is_none());
+    }
+}
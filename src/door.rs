@@ -1,6 +1,7 @@
 pub struct Door {
     pub offset: f32,
     pub opening: bool,
+    pub closing: bool,
     pub timer: f32,
     pub pos: usize,
 }
@@ -10,13 +11,30 @@ impl Door {
         Self {
             offset,
             opening,
+            closing: false,
             timer,
             pos,
         }
     }
 
+    /// Opens a closing door again, leaving the doorway free right away so whatever stepped
+    /// into it isn't stuck in a solid cell
+    pub fn reopen(&mut self, solid: &mut [bool]) {
+        self.closing = false;
+        self.opening = true;
+        solid[self.pos] = false;
+    }
+
     pub fn update(&mut self, dt: f32, solid: &mut Vec<bool>) {
-        if self.offset > 0.001 {
+        if self.closing {
+            // Solid as soon as it starts closing so nothing walks into it
+            solid[self.pos] = true;
+            self.offset += 0.5 * dt;
+            if self.offset >= 1.0 {
+                self.offset = 1.0;
+                self.closing = false;
+            }
+        } else if self.offset > 0.001 {
             self.offset -= 0.5 * dt;
         } else {
             self.opening = false;
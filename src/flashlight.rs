@@ -0,0 +1,93 @@
+/// Light carried by the player, shining a cone along the view
+#[derive(Copy, Clone)]
+pub struct Flashlight {
+    pub on: bool,
+    pub color: [f32; 3],
+    /// Distance where the beam has faded out
+    pub range: f32,
+    /// Tangents of the half angles of the fully lit core and of the edge of the beam
+    inner: f32,
+    outer: f32,
+    /// Charge left, from 0 to 1
+    pub battery: f32,
+    /// Charge used per second while on
+    drain: f32,
+}
+
+impl Flashlight {
+    /// `cone` and `edge` are the half angle of the beam and the width of its soft edge in
+    /// degrees, `battery` is how many seconds a full charge lasts
+    pub fn new(color: [f32; 3], range: f32, cone: f32, edge: f32, battery: f32) -> Self {
+        Self {
+            on: true,
+            color,
+            range,
+            inner: (cone - edge).max(0.0).to_radians().tan(),
+            outer: cone.to_radians().tan(),
+            battery: 1.0,
+            drain: 1.0 / battery,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.on = !self.on;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        if self.on {
+            self.battery = (self.battery - self.drain * dt).max(0.0);
+        }
+    }
+
+    /// Brightness of the beam on a surface `distance` away, seen `offset` from the middle of
+    /// the view. The offset holds the tangents of the horizontal and vertical angles
+    #[inline(always)]
+    pub fn light(&self, distance: f32, offset: [f32; 2]) -> f32 {
+        if !self.on || self.battery <= 0.0 {
+            return 0.0;
+        }
+        let spread = (offset[0] * offset[0] + offset[1] * offset[1]).sqrt();
+        if spread >= self.outer {
+            return 0.0;
+        }
+        let cone = smoothstep(self.outer, self.inner, spread);
+        let reach = (1.0 - distance / self.range).max(0.0);
+        // Weak batteries dim the beam over their last fifth
+        let charge = (self.battery * 5.0).min(1.0);
+        (3.0 / (distance * distance)).min(1.5) * reach * cone * charge
+    }
+}
+
+impl Default for Flashlight {
+    fn default() -> Self {
+        Self::new([1.0, 0.9, 0.8], 12.0, 20.0, 6.0, 300.0)
+    }
+}
+
+#[inline(always)]
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn beam_has_a_soft_edge_and_drains() {
+        let mut flashlight = Flashlight::new([1.0; 3], 10.0, 20.0, 10.0, 10.0);
+        let center = flashlight.light(2.0, [0.0, 0.0]);
+        let edge = flashlight.light(2.0, [15f32.to_radians().tan(), 0.0]);
+        assert_eq!(center, 0.75 * 0.8);
+        assert!(edge > 0.0 && edge < center);
+        assert_eq!(flashlight.light(2.0, [0.0, 25f32.to_radians().tan()]), 0.0);
+        assert_eq!(flashlight.light(10.0, [0.0, 0.0]), 0.0);
+
+        flashlight.update(9.5);
+        assert!(flashlight.light(2.0, [0.0, 0.0]) < center);
+        flashlight.update(1.0);
+        assert_eq!(flashlight.battery, 0.0);
+        assert_eq!(flashlight.light(2.0, [0.0, 0.0]), 0.0);
+    }
+}
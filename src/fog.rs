@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+/// How fog thickens between its start and end distances
+#[derive(Copy, Clone, PartialEq)]
+pub enum Falloff {
+    Linear,
+    Exponential,
+}
+
+impl FromStr for Falloff {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "linear" => Falloff::Linear,
+            "exponential" => Falloff::Exponential,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Fog blending surfaces into `color` with their depth, nothing past `end` can be seen
+#[derive(Copy, Clone)]
+pub struct Fog {
+    /// rgb from 0 to 255, alpha stays 0
+    pub color: [f32; 4],
+    pub start: f32,
+    pub end: f32,
+    /// How quickly exponential fog closes in after `start`
+    pub density: f32,
+    pub falloff: Falloff,
+}
+
+impl Fog {
+    /// Share of the fog color in a surface at `depth`, from 0 to 1
+    #[inline(always)]
+    pub fn amount(&self, depth: f32) -> f32 {
+        let t = ((depth - self.start) / (self.end - self.start)).clamp(0.0, 1.0);
+        match self.falloff {
+            Falloff::Linear => t,
+            // Scaled so it is fully fogged at the end like linear fog
+            Falloff::Exponential => {
+                (1.0 - (-self.density * t).exp()) / (1.0 - (-self.density).exp())
+            }
+        }
+    }
+}
+
+/// Fog of the surfaces inside a rectangle of map cells instead of the level's, corners
+/// included
+pub struct Zone {
+    pub min: [usize; 2],
+    pub max: [usize; 2],
+    pub fog: Fog,
+}
+
+/// Fog settings of a level. Surfaces are fogged by the cell they are in, so a zone looks
+/// foggy from outside of it too
+#[derive(Default)]
+pub struct Atmosphere {
+    pub fog: Option<Fog>,
+    pub zones: Vec<Zone>,
+    /// Fog of every map cell of both storeys
+    cells: Vec<Option<Fog>>,
+    /// Farthest distance anything can be seen at, `None` if some cells have no fog
+    pub reach: Option<f32>,
+}
+
+impl Atmosphere {
+    /// Works out the fog of every map cell once the zones are read, the last zone containing
+    /// a cell wins over the level's fog
+    pub fn fill(&mut self, map_size: (usize, usize)) {
+        let layer: Vec<Option<Fog>> = (0..map_size.0 * map_size.1)
+            .map(|cell| {
+                let (x, y) = (cell % map_size.0, cell / map_size.0);
+                self.zones
+                    .iter()
+                    .rev()
+                    .find(|zone| {
+                        (zone.min[0]..=zone.max[0]).contains(&x)
+                            && (zone.min[1]..=zone.max[1]).contains(&y)
+                    })
+                    .map(|zone| zone.fog)
+                    .or(self.fog)
+            })
+            .collect();
+        self.cells = [layer.clone(), layer].concat();
+        self.reach = self
+            .cells
+            .iter()
+            .try_fold(0.0f32, |reach, fog| Some(reach.max(fog.as_ref()?.end)));
+    }
+
+    /// Fog of the surfaces in a map cell, on either storey
+    #[inline(always)]
+    pub fn at(&self, cell: usize) -> Option<&Fog> {
+        self.cells[cell].as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn fog_thickens_until_the_end() {
+        let mut fog = Fog {
+            color: [0.0; 4],
+            start: 2.0,
+            end: 10.0,
+            density: 3.0,
+            falloff: Falloff::Linear,
+        };
+        assert_eq!(fog.amount(1.0), 0.0);
+        assert_eq!(fog.amount(6.0), 0.5);
+        assert_eq!(fog.amount(20.0), 1.0);
+        fog.falloff = Falloff::Exponential;
+        assert!(fog.amount(6.0) > 0.5);
+        assert!((fog.amount(10.0) - 1.0).abs() < 1e-6);
+
+        let mut atmosphere = Atmosphere {
+            zones: vec![Zone {
+                min: [3, 3],
+                max: [5, 4],
+                fog,
+            }],
+            ..Default::default()
+        };
+        atmosphere.fill((8, 6));
+        assert!(atmosphere.at(5 + 4 * 8).is_some());
+        assert!(atmosphere.at(6 + 4 * 8).is_none());
+        // The zone covers the upper storey too, cells outside it can be seen forever
+        assert!(atmosphere.at(4 + 3 * 8 + 48).is_some());
+        assert_eq!(atmosphere.reach, None);
+        atmosphere.fog = Some(Fog { end: 30.0, ..fog });
+        atmosphere.fill((8, 6));
+        assert_eq!(atmosphere.reach, Some(30.0));
+    }
+}
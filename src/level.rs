@@ -0,0 +1,308 @@
+use std::{collections::HashMap, path::Path};
+
+use ggez::{Context, GameError, GameResult};
+
+use crate::{
+    decal::{Decal, Surface},
+    flashlight::Flashlight,
+    fog::{Atmosphere, Falloff, Fog, Zone},
+    lighting::{Light, Profile},
+    liquid::Liquid,
+    map::{Mirror, Type},
+    particle::{self, Emitter, Style},
+    portal::Portal,
+    sprite::{Definition, Sprite},
+    texture::{Atlas, UNITS},
+    utilities::{datafile, vector2::Vector2},
+    Orientation,
+};
+
+/// Wall type and textures of one color of the wall layer
+pub struct Legend {
+    pub color: [u8; 3],
+    pub wall_type: Type,
+    pub texture: usize,
+    pub upper: usize,
+    pub frame: Option<usize>,
+}
+
+pub struct Level {
+    pub walls: String,
+    pub floors: String,
+    pub ceilings: String,
+    pub sky: String,
+    pub legend: Vec<Legend>,
+    pub palette: HashMap<usize, usize>,
+    pub sprites: Vec<Sprite>,
+    pub lights: Vec<Light>,
+    pub atmosphere: Atmosphere,
+    pub flashlight: Flashlight,
+    /// Strength and radius of the ambient occlusion
+    pub occlusion: (f32, f32),
+    pub mirror: Mirror,
+    pub portals: Vec<Portal>,
+    /// Liquids by their floor texture
+    pub liquids: HashMap<usize, Liquid>,
+    pub decals: Vec<(Surface, Decal)>,
+    pub emitters: Vec<Emitter>,
+}
+
+impl Level {
+    pub fn load(
+        ctx: &mut Context,
+        path: &Path,
+        atlas: &Atlas,
+        definitions: &HashMap<String, Definition>,
+        styles: &[Style],
+        map_size: (usize, usize),
+    ) -> GameResult<Self> {
+        let mut layers = HashMap::new();
+        let mut legend = vec![];
+        let mut palette = HashMap::new();
+        let mut sprites = vec![];
+        let mut lights = vec![];
+        let mut atmosphere = Atmosphere::default();
+        let mut flashlight = Flashlight::default();
+        let mut occlusion = (0.6, 0.5);
+        let mut mirror = Mirror::default();
+        let mut portals = vec![];
+        let mut liquids = HashMap::new();
+        let mut decals = vec![];
+        let mut emitters = vec![];
+        for entry in datafile::read(ctx, path)? {
+            match entry.section.as_str() {
+                "layers" => {
+                    layers.insert(entry.name(0)?.to_string(), entry.name(1)?.to_string());
+                }
+                "walls" => {
+                    let texture = atlas.id(entry.name(4)?)?;
+                    let upper = match entry.option::<String>("upper")? {
+                        Some(name) => atlas.id(&name)?,
+                        None => texture,
+                    };
+                    let frame = match entry.option::<String>("frame")? {
+                        Some(name) => Some(atlas.id(&name)?),
+                        None => None,
+                    };
+                    legend.push(Legend {
+                        color: [entry.field(0)?, entry.field(1)?, entry.field(2)?],
+                        wall_type: entry.field(3)?,
+                        texture,
+                        upper,
+                        frame,
+                    });
+                }
+                "textures" => {
+                    palette.insert(entry.field(0)?, atlas.id(entry.name(1)?)?);
+                }
+                "sprites" => {
+                    let name = entry.name(0)?;
+                    let definition = definitions
+                        .get(name)
+                        .ok_or_else(|| entry.error(&format!("unknown sprite '{}'", name)))?;
+                    // Sprites play their first clip unless told otherwise
+                    let clip = match entry.option::<String>("clip")? {
+                        Some(clip) => Some(definition.clip(&clip).ok_or_else(|| {
+                            entry.error(&format!("'{}' has no clip '{}'", name, clip))
+                        })?),
+                        None => (!definition.clips.is_empty()).then_some(0),
+                    };
+                    sprites.push(Sprite::new(
+                        definition.clone(),
+                        Vector2::new(entry.field(1)?, entry.field(2)?),
+                        entry.option::<f32>("facing")?.unwrap_or(0.0).to_radians(),
+                        entry.option("storey")?.unwrap_or(0.0),
+                        clip,
+                    ));
+                }
+                "lights" => {
+                    let (x, y): (usize, usize) = (entry.field(0)?, entry.field(1)?);
+                    let storey: usize = entry.option("storey")?.unwrap_or(0);
+                    if x >= map_size.0 || y >= map_size.1 || storey > 1 {
+                        return Err(entry.error("light is outside of the map"));
+                    }
+                    lights.push(Light {
+                        cell: x + y * map_size.0 + storey * map_size.0 * map_size.1,
+                        color: [entry.field(2)?, entry.field(3)?, entry.field(4)?],
+                        radius: entry.field(5)?,
+                        flicker: match entry.option::<String>("flicker")? {
+                            Some(name) => name
+                                .parse()
+                                .map_err(|_| entry.error(&format!("unknown flicker '{}'", name)))?,
+                            None => Profile::Steady,
+                        },
+                    });
+                }
+                "fog" => atmosphere.fog = Some(fog(&entry, 0)?),
+                "fog_zones" => {
+                    let zone = Zone {
+                        min: [entry.field(0)?, entry.field(1)?],
+                        max: [entry.field(2)?, entry.field(3)?],
+                        fog: fog(&entry, 4)?,
+                    };
+                    if zone.max[0] >= map_size.0 || zone.max[1] >= map_size.1 {
+                        return Err(entry.error("fog zone is outside of the map"));
+                    }
+                    atmosphere.zones.push(zone);
+                }
+                "flashlight" => {
+                    let cone = entry.option("cone")?.unwrap_or(20.0);
+                    let edge = entry.option("edge")?.unwrap_or(6.0);
+                    if cone >= 90.0 || edge <= 0.0 || edge > cone {
+                        return Err(
+                            entry.error("the edge has to be inside a cone under 90 degrees")
+                        );
+                    }
+                    flashlight = Flashlight::new(
+                        [entry.field(0)?, entry.field(1)?, entry.field(2)?],
+                        entry.field(3)?,
+                        cone,
+                        edge,
+                        entry.option("battery")?.unwrap_or(300.0),
+                    );
+                }
+                "occlusion" => {
+                    occlusion = (entry.field(0)?, entry.field(1)?);
+                    if !(0.0..=1.0).contains(&occlusion.0) || occlusion.1 <= 0.0 {
+                        return Err(
+                            entry.error("occlusion needs a strength from 0 to 1 and a radius")
+                        );
+                    }
+                }
+                "mirror" => {
+                    mirror = Mirror {
+                        tint: [entry.field(0)?, entry.field(1)?, entry.field(2)?],
+                        bounces: entry.field(3)?,
+                    };
+                }
+                "portals" => {
+                    let portal = Portal {
+                        cell: [entry.field(0)?, entry.field(1)?],
+                        target: [entry.field(2)?, entry.field(3)?],
+                        turns: match entry.option::<usize>("rotation")?.unwrap_or(0) {
+                            rotation if rotation % 90 == 0 => rotation / 90 % 4,
+                            _ => return Err(entry.error("portals turn in steps of 90 degrees")),
+                        },
+                    };
+                    if [portal.cell, portal.target]
+                        .iter()
+                        .any(|[x, y]| *x >= map_size.0 || *y >= map_size.1)
+                    {
+                        return Err(entry.error("portal is outside of the map"));
+                    }
+                    portals.push(portal);
+                }
+                "liquids" => {
+                    let liquid = Liquid {
+                        depth: entry.field(1)?,
+                        speed: entry.field(2)?,
+                        waves: entry.field(3)?,
+                        glow: [entry.field(4)?, entry.field(5)?, entry.field(6)?],
+                        damage: entry.option("damage")?.unwrap_or(0.0),
+                    };
+                    if !(0.0..0.5).contains(&liquid.depth) || liquid.speed <= 0.0 {
+                        return Err(entry
+                            .error("liquids need a depth under half a wall and a positive speed"));
+                    }
+                    liquids.insert(atlas.id(entry.name(0)?)?, liquid);
+                }
+                "emitters" => {
+                    let name = entry.name(0)?;
+                    let pos: [f32; 3] = [entry.field(1)?, entry.field(2)?, entry.field(3)?];
+                    let size = [map_size.0 as f32, map_size.1 as f32, 2.0];
+                    if pos
+                        .iter()
+                        .zip(size)
+                        .any(|(p, size)| !(0.0..size).contains(p))
+                    {
+                        return Err(entry.error("emitter is outside of the map"));
+                    }
+                    emitters.push(Emitter {
+                        style: particle::find(styles, name)
+                            .ok_or_else(|| entry.error(&format!("unknown style '{}'", name)))?,
+                        pos,
+                        spread: entry.option("spread")?.unwrap_or(0.0),
+                        rate: entry.field(4)?,
+                        carry: 0.0,
+                    });
+                }
+                "decals" => {
+                    let (x, y): (usize, usize) = (entry.field(1)?, entry.field(2)?);
+                    let storey: usize = entry.option("storey")?.unwrap_or(0);
+                    if x >= map_size.0 || y >= map_size.1 || storey > 1 {
+                        return Err(entry.error("decal is outside of the map"));
+                    }
+                    let cell = x + y * map_size.0 + storey * map_size.0 * map_size.1;
+                    let surface = match entry.option::<String>("face")?.as_deref() {
+                        None if storey == 0 => Surface::Floor(cell),
+                        None => return Err(entry.error("only walls have decals upstairs")),
+                        Some("n") => Surface::Wall(cell, Orientation::N),
+                        Some("e") => Surface::Wall(cell, Orientation::E),
+                        Some("s") => Surface::Wall(cell, Orientation::S),
+                        Some("w") => Surface::Wall(cell, Orientation::W),
+                        Some(face) => return Err(entry.error(&format!("unknown face '{}'", face))),
+                    };
+                    let units = UNITS as f32;
+                    let center: [f32; 2] = [entry.field(3)?, entry.field(4)?];
+                    decals.push((
+                        surface,
+                        Decal::new(
+                            atlas.id(entry.name(0)?)?,
+                            center.map(|c| c * units),
+                            entry.field::<f32>(5)? * units,
+                        ),
+                    ));
+                }
+                section => return Err(entry.error(&format!("unknown section '{}'", section))),
+            }
+        }
+        atmosphere.fill(map_size);
+        let mut layer = |name: &str| {
+            layers.remove(name).ok_or_else(|| {
+                GameError::ResourceLoadError(format!("level is missing the {} layer", name))
+            })
+        };
+        Ok(Self {
+            walls: layer("walls")?,
+            floors: layer("floors")?,
+            ceilings: layer("ceilings")?,
+            sky: layer("sky")?,
+            legend,
+            palette,
+            sprites,
+            lights,
+            atmosphere,
+            flashlight,
+            occlusion,
+            mirror,
+            portals,
+            liquids,
+            decals,
+            emitters,
+        })
+    }
+}
+
+/// Fog settings `r g b start end [density=n] [falloff=linear|exponential]` from field `first`
+fn fog(entry: &datafile::Entry, first: usize) -> GameResult<Fog> {
+    let field = |i: usize| entry.field::<f32>(first + i);
+    let fog = Fog {
+        color: [field(0)?, field(1)?, field(2)?, 0.0],
+        start: field(3)?,
+        end: field(4)?,
+        density: entry.option("density")?.unwrap_or(3.0),
+        falloff: match entry.option::<String>("falloff")? {
+            Some(name) => name
+                .parse()
+                .map_err(|_| entry.error(&format!("unknown falloff '{}'", name)))?,
+            None => Falloff::Linear,
+        },
+    };
+    if fog.end <= fog.start {
+        return Err(entry.error("fog has to end after it starts"));
+    }
+    if fog.density <= 0.0 {
+        return Err(entry.error("fog density has to be positive"));
+    }
+    Ok(fog)
+}
@@ -5,29 +5,54 @@ use ggez::event::{EventHandler, KeyCode, KeyMods};
 use ggez::graphics::{self, Color, DrawParam};
 use ggez::input::keyboard::is_key_pressed;
 use ggez::{audio, timer, Context, GameResult};
+mod decal;
 mod door;
+mod flashlight;
+mod fog;
+mod level;
 mod lighting;
+mod liquid;
 mod map;
+mod occlusion;
+mod particle;
 mod player;
+mod portal;
+mod post;
 mod render;
 mod screen;
 mod sprite;
+mod texture;
 mod utilities;
-use lighting::{Lighting, Torch};
+use decal::{Decal, Decals};
+use fog::Atmosphere;
+use level::Level;
+use lighting::{Light, Lighting, Profile};
 use map::{Map, Type};
 use num::clamp;
+use occlusion::Occlusion;
+use particle::{Particles, Style};
 use player::Player;
+use post::Post;
 use rayon::prelude::*;
+use render::{Column, Views};
 use screen::Screen;
 use sprite::Sprite;
+use texture::Atlas;
 use utilities::input::{mouse_grabbed_and_hidden, set_mouse_location};
+use utilities::math::circle_overlaps_cell;
 use utilities::vector2::Vector2;
 //https://mynoise.net/NoiseMachines/dungeonRPGSoundscapeGenerator.php?l=32343600005816020035&mt=1&tm=1
 use crate::utilities::input::get_delta;
 
 const PI: f32 = std::f32::consts::PI;
 const RAYSPERPIXEL: usize = 2;
-const FOV: f32 = 45.0;
+const PLAYER_RADIUS: f32 = 0.3125;
+const MAP_SIZE: (usize, usize) = (40, 25);
+const LANTERN_COLOR: [f32; 3] = [1.2, 1.0, 0.7];
+const LANTERN_RADIUS: usize = 6;
+/// Decals added while playing before the oldest ones get removed
+const DECAL_LIMIT: usize = 64;
+pub(crate) const FOV: f32 = 45.0;
 #[allow(dead_code)]
 pub struct MainState {
     player: Player,
@@ -37,12 +62,24 @@ pub struct MainState {
     angles: Vec<f32>,
     buffer_floors: Vec<f32>,
     buffer_walking: Vec<f32>,
-    sky: Sky,
+    depth_buffer: Vec<f32>,
     screen: Screen,
+    post: Post,
     sprites: Vec<Sprite>,
     lighting_1: Lighting,
-    torch: Torch,
+    occlusion: Occlusion,
+    decals: Decals,
+    particles: Particles,
+    /// Particle styles thrown out by opening doors and by hits
+    dust: usize,
+    sparks: usize,
+    atmosphere: Atmosphere,
+    /// Decal left on the wall in the middle of the view with G
+    bullet_hole: usize,
+    /// Light carried by the player, toggled with T
+    lantern: Option<usize>,
     sounds: Sound,
+    time: f32,
 }
 
 impl MainState {
@@ -52,21 +89,11 @@ impl MainState {
         let pos = Vector2::new(8.5, 12.5);
         let dir_norm = Vector2::new(0.0f32, -1.0); // Player direction
         let plane = Vector2::new((FOV.to_radians() * 0.5).tan(), 0.0); //Camera plane vector
-        let map_size = (40, 25);
+        let map_size = MAP_SIZE;
         let cell_size = 128.0;
-        let minimap = graphics::Image::new(ctx, "/minimap.png")?;
-        let minimap_sb =
-            graphics::spritebatch::SpriteBatch::new(graphics::Image::new(ctx, "/sb.png")?);
-        let map = Map::new(
-            ctx,
-            Path::new("/map.png"),
-            Path::new("/floor.png"),
-            minimap,
-            minimap_sb,
-            map_size,
-        )?;
+        let (atlas, styles, level, map) = load_world(ctx, map_size)?;
 
-        let player = Player::new(
+        let mut player = Player::new(
             ctx,
             pos,
             dir_norm,
@@ -76,6 +103,7 @@ impl MainState {
             150.0,
         )?;
 
+        player.flashlight = level.flashlight;
         set_mouse_location(ctx, Vector2::new(w * 0.5, h * 0.5)).unwrap();
 
         let angles: Vec<f32> = (0..w as usize / RAYSPERPIXEL)
@@ -93,88 +121,36 @@ impl MainState {
             .map(|i| ((i as f32) / 150.0 * 2.0 * PI).sin())
             .collect();
 
-        let mut skyimg = graphics::Image::new(ctx, "/sky2.png")?;
-        skyimg.set_wrap(graphics::WrapMode::Tile, graphics::WrapMode::Mirror);
-        skyimg.set_filter(graphics::FilterMode::Nearest);
-        let mut sb = graphics::spritebatch::SpriteBatch::new(skyimg);
-        let idx = sb.add(DrawParam::default());
-        let sky = Sky { sb, idx };
-
-        let wall_textures = graphics::Image::new(ctx, "/wall128.png")?.to_rgba8(ctx)?;
-
-        let sprite_textures = graphics::Image::new(ctx, "/sprite128.png")?.to_rgba8(ctx)?;
-
-        let mut screen = unsafe { Screen::new(h, w, 128, 128 * 8) };
-        screen.textures(wall_textures, sprite_textures);
-
-        let sprites = vec![
-            Sprite::new(sprite::SpriteType::Armor, Vector2::new(7.5, 7.5), 0.0),
-            //Sprite::new(sprite::SpriteType::Armor, Vector2::new(7.5, 9.5)),
-            //Sprite::new(sprite::SpriteType::CandleHolder, Vector2::new(12.5, 12.5)),
-            //Sprite::new(sprite::SpriteType::Bat, Vector2::new(6.5, 12.5)),
-            Sprite::new(sprite::SpriteType::Torch, Vector2::new(13.5, 1.048), 0.0),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(8.5, 24.0 - 0.048),
-                0.0,
-            ),
-            Sprite::new(sprite::SpriteType::Torch, Vector2::new(2.048, 3.5), 0.0),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(16.0 - 0.048, 6.5),
-                0.0,
-            ),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(28.5, 24.0 - 0.048),
-                0.0,
-            ),
-            Sprite::new(sprite::SpriteType::Torch, Vector2::new(24.5, 1.048), 0.0),
-            Sprite::new(sprite::SpriteType::Torch, Vector2::new(30.5, 1.048), 0.0),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(27.0 - 0.048, 8.5),
-                0.0,
-            ),
-            Sprite::new(sprite::SpriteType::Torch, Vector2::new(28.048, 8.5), 0.0),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(32.0 - 0.048, 8.5),
-                0.0,
-            ),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(39.0 - 0.048, 20.5),
-                -player.planedist,
-            ),
-            Sprite::new(
-                sprite::SpriteType::Torch,
-                Vector2::new(39.0 - 0.048, 10.5),
-                -player.planedist,
-            ),
-            //Sprite::new(sprite::SpriteType::Gore, Vector2::new(13.0, 3.0)),
-        ];
-
-        let lighting_1 = lighting::Lighting::new(
-            vec![
-                2 + map_size.0 * 3,
-                15 + map_size.0 * 6,
-                8 + map_size.0 * 23,
-                28 + map_size.0 * 23,
-                24 + map_size.0 * 1,
-                30 + map_size.0 * 1,
-                13 + map_size.0 * 1,
-                26 + map_size.0 * 8,
-                28 + map_size.0 * 8,
-                31 + map_size.0 * 8,
-                39 + map_size.0 * 10 + map_size.0 * map_size.1,
-                39 + map_size.0 * 20 + map_size.0 * map_size.1,
-            ],
+        let sky_image = graphics::Image::new(ctx, level.sky.as_str())?;
+        let sky_size = (sky_image.width() as usize, sky_image.height() as usize);
+        let sky_texture = sky_image.to_rgba8(ctx)?;
+
+        let mut screen = unsafe { Screen::new(h, w, atlas) };
+        screen.sky(sky_texture, sky_size);
+        screen.flashlight(level.flashlight.color);
+
+        let lighting_1 = Lighting::load(
+            ctx,
+            Path::new("/level.bake"),
+            &level.lights,
             &map.solid,
             map_size,
         );
 
-        let torch = lighting::Torch::default();
+        let (strength, radius) = level.occlusion;
+        let occlusion = Occlusion::new(&map.solid, &map.open_sky, map_size, strength, radius);
+
+        let mut decals = Decals::new(map_size, DECAL_LIMIT);
+        for &(surface, decal) in level.decals.iter() {
+            decals.place(surface, decal);
+        }
+        let bullet_hole = screen.atlas.id("bullet_hole")?;
+
+        let particles = Particles::new(styles, level.emitters, &map.solid, map_size);
+        let dust = particles.style("dust")?;
+        let sparks = particles.style("sparks")?;
+
+        let post = Post::load(ctx, Path::new("/post.txt"))?;
 
         let mut sounds = Sound::new(ctx)?;
         sounds.walking.set_volume(0.02);
@@ -187,12 +163,21 @@ impl MainState {
             angles,
             buffer_floors,
             buffer_walking,
-            sky,
+            depth_buffer: vec![f32::INFINITY; h as usize * (w as usize / RAYSPERPIXEL)],
             screen,
-            sprites,
+            post,
+            sprites: level.sprites,
             lighting_1,
-            torch,
+            occlusion,
+            decals,
+            particles,
+            dust,
+            sparks,
+            bullet_hole,
+            atmosphere: level.atmosphere,
+            lantern: None,
             sounds,
+            time: 0.0,
         })
     }
 
@@ -226,7 +211,11 @@ impl MainState {
         if self.player.current_wall == Type::Stairs {
             dir *= 0.4;
         }
-        let yoffset = 0.3125;
+        if let Some(liquid) = self.player.liquid {
+            dir *= liquid.speed;
+        }
+        let yoffset = PLAYER_RADIUS;
+        let start = self.player.pos;
 
         self.player.walking = false;
 
@@ -316,18 +305,8 @@ impl MainState {
             self.player.walking = true;
         }
 
-        if is_key_pressed(ctx, KeyCode::Space) {
-            let check_front = self.player.pos + self.player.dir_norm * 1.5;
-            let pos_door = (check_front.x) as usize + (check_front.y) as usize * self.map_size.0;
-
-            if self.map.walls[pos_door] == Type::WoodenDoor {
-                let door = self.map.doors.get_mut(&pos_door).expect("Cant find door");
-                if !door.opening {
-                    door.timer = timer::time_since_start(ctx).as_secs_f32();
-                    door.opening = true;
-                }
-            }
-        }
+        self.collide_sprites(start);
+        self.go_through_portal(start);
 
         if is_key_pressed(ctx, KeyCode::LControl) {
             if self.player.height > -300.0 {
@@ -345,17 +324,171 @@ impl MainState {
             self.player.jump -= 10.0;
         }
     }
+
+    /// Opens the door in front of the player, or closes it again unless something stands in
+    /// the doorway
+    fn use_door(&mut self, ctx: &mut Context) {
+        let check_front = self.player.pos + self.player.dir_norm * 1.5;
+        let pos_door = (check_front.x) as usize + (check_front.y) as usize * self.map_size.0;
+
+        if self.map.walls[pos_door] == Type::WoodenDoor {
+            let occupied = self.cell_occupied(pos_door);
+            let door = self.map.doors.get_mut(&pos_door).expect("Cant find door");
+            if !door.opening && !door.closing {
+                if door.offset > 0.001 {
+                    door.timer = timer::time_since_start(ctx).as_secs_f32();
+                    door.opening = true;
+                    // Dust falls out in front of the door, the doorway stays solid until
+                    // it is open
+                    let (x, y) = (pos_door % self.map_size.0, pos_door / self.map_size.0);
+                    let dir = self.player.dir_norm;
+                    let step = if dir.x.abs() > dir.y.abs() {
+                        [-0.7 * dir.x.signum(), 0.0]
+                    } else {
+                        [0.0, -0.7 * dir.y.signum()]
+                    };
+                    let pos = [x as f32 + 0.5 + step[0], y as f32 + 0.5 + step[1], 0.1];
+                    self.particles.burst(self.dust, pos, 0.2, 40);
+                } else if !occupied {
+                    door.closing = true;
+                }
+            }
+        }
+    }
+
+    /// Slides the player along solid sprites it ran into while moving from `from`
+    fn collide_sprites(&mut self, from: Vector2<f32>) {
+        let to = self.player.pos;
+        let blocked = |from, to| {
+            self.sprites
+                .iter()
+                .any(|sprite| sprite.blocks(from, to, PLAYER_RADIUS))
+        };
+        let mut pos = from;
+        if !blocked(pos, Vector2::new(to.x, pos.y)) {
+            pos.x = to.x;
+        }
+        if !blocked(pos, Vector2::new(pos.x, to.y)) {
+            pos.y = to.y;
+        }
+        self.player.pos = pos;
+    }
+
+    /// Teleports the player who walked from `from` into a portal cell, turning the view with
+    /// the portal
+    fn go_through_portal(&mut self, from: Vector2<f32>) {
+        let cell = |pos: Vector2<f32>| pos.x as usize + pos.y as usize * self.map_size.0;
+        if cell(from) == cell(self.player.pos) {
+            return;
+        }
+        if let Some(portal) = self.map.portals.get(&cell(self.player.pos)) {
+            self.player.pos = portal.position(self.player.pos);
+            self.player.dir_norm = portal.direction(self.player.dir_norm);
+            self.player.plane = portal.direction(self.player.plane);
+        }
+    }
+
+    /// Lighting cell the player is in, on the upper storey when high enough
+    fn player_cell(&self) -> usize {
+        let storey = (self.player.jump * 2.0 > self.player.planedist) as usize;
+        self.player.pos.x as usize
+            + self.player.pos.y as usize * self.map_size.0
+            + storey * self.map_size.0 * self.map_size.1
+    }
+
+    /// Whether the player or a solid sprite stands in a map cell. Sprites stay where the level
+    /// puts them, so the player is the only thing that moves into a cell
+    fn cell_occupied(&self, cell: usize) -> bool {
+        let cell = [cell % self.map_size.0, cell / self.map_size.0];
+        circle_overlaps_cell(self.player.pos, PLAYER_RADIUS, cell)
+            || self.sprites.iter().any(|sprite| sprite.overlaps_cell(cell))
+    }
+}
+
+/// Textures, particle styles, level and map
+fn load_world(
+    ctx: &mut Context,
+    map_size: (usize, usize),
+) -> GameResult<(Atlas, Vec<Style>, Level, Map)> {
+    let minimap = graphics::Image::new(ctx, "/minimap.png")?;
+    let minimap_sb = graphics::spritebatch::SpriteBatch::new(graphics::Image::new(ctx, "/sb.png")?);
+    let atlas = Atlas::load(ctx, Path::new("/textures.txt"))?;
+    let definitions = sprite::load_definitions(ctx, Path::new("/sprites.txt"), &atlas)?;
+    let styles = particle::load_styles(ctx, Path::new("/particles.txt"))?;
+    let level = Level::load(
+        ctx,
+        Path::new("/level.txt"),
+        &atlas,
+        &definitions,
+        &styles,
+        map_size,
+    )?;
+    let map = Map::new(ctx, &level, minimap, minimap_sb, map_size)?;
+    Ok((atlas, styles, level, map))
+}
+
+/// Spreads the lights of the level with and without shadows ahead of time and writes them to
+/// `path`, where `MainState::new` loads them from instead of spreading them itself
+pub fn bake(ctx: &mut Context, path: &Path) -> GameResult {
+    let (_, _, level, map) = load_world(ctx, MAP_SIZE)?;
+    std::fs::write(path, lighting::bake(&level.lights, &map.solid, MAP_SIZE))?;
+    Ok(())
 }
 
 impl EventHandler for MainState {
-    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, _: KeyMods, _: bool) {
+    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, _: KeyMods, repeat: bool) {
         match keycode {
+            // Only on the press itself, holding the key would open and close doors in turn
+            KeyCode::Space if !repeat => self.use_door(ctx),
             KeyCode::L => {
                 self.lighting_1.switch = !self.lighting_1.switch;
             }
             KeyCode::K => {
                 self.lighting_1.smooth_switch = !self.lighting_1.smooth_switch;
             }
+            KeyCode::H => {
+                let shadows = !self.lighting_1.shadows();
+                self.lighting_1.set_shadows(shadows);
+            }
+            KeyCode::T => {
+                self.lantern = match self.lantern {
+                    Some(lantern) => {
+                        self.lighting_1.remove_light(lantern);
+                        None
+                    }
+                    None => Some(self.lighting_1.add_light(Light {
+                        cell: self.player_cell(),
+                        color: LANTERN_COLOR,
+                        radius: LANTERN_RADIUS,
+                        flicker: Profile::Steady,
+                    })),
+                };
+            }
+            KeyCode::O => {
+                self.occlusion.switch = !self.occlusion.switch;
+            }
+            KeyCode::F => {
+                self.player.flashlight.toggle();
+            }
+            KeyCode::G => {
+                if let Some((surface, center, point)) = render::aim(self) {
+                    let decal = Decal::new(self.bullet_hole, center, 16.0);
+                    self.decals.add(surface, decal);
+                    // Sparks fly from just in front of the wall
+                    let back = self.player.dir_norm * 0.05;
+                    let pos = [point[0] - back.x, point[1] - back.y, point[2]];
+                    self.particles.burst(self.sparks, pos, 0.0, 24);
+                }
+            }
+            KeyCode::M => {
+                self.screen.quality.mipmaps = !self.screen.quality.mipmaps;
+            }
+            KeyCode::B => {
+                self.screen.quality.bilinear = !self.screen.quality.bilinear;
+            }
+            KeyCode::P => {
+                self.post.switch = !self.post.switch;
+            }
             KeyCode::Escape => ggez::event::quit(ctx),
             _ => (),
         }
@@ -363,15 +496,27 @@ impl EventHandler for MainState {
     fn update(&mut self, ctx: &mut Context) -> GameResult {
         let time = timer::time_since_start(ctx).as_secs_f32();
         let dt = ggez::timer::delta(ctx).as_secs_f32();
+        self.time = time;
 
         self.handle_input(ctx);
+        self.player.flashlight.update(dt);
 
+        let cell = self.player.pos.x as usize + self.player.pos.y as usize * self.map_size.0;
+        let health = self.player.health;
         self.player.update(
-            self.map.walls
-                [self.player.pos.x as usize + self.player.pos.y as usize * self.map_size.0],
+            self.map.walls[cell],
+            self.map.liquids[cell],
             &self.buffer_walking,
             time,
+            dt,
         );
+        if self.player.health < health {
+            self.post.hurt();
+        }
+        if self.player.health <= 0.0 {
+            self.player.respawn();
+        }
+        self.post.update(dt);
 
         if self.map.walls[self.player.pos.x as usize + self.player.pos.y as usize * self.map_size.0]
             == Type::Stairs
@@ -379,49 +524,51 @@ impl EventHandler for MainState {
             self.player.height = (self.player.pos.x.fract()) * self.player.planedist;
         }
 
-        if self.player.walking {
-            if self.sounds.walking.paused() {
-                self.sounds.walking.resume();
-            } else if !self.sounds.walking.playing() {
-                self.sounds.walking.play_later().unwrap();
-            }
-        } else if self.sounds.walking.playing() {
-            self.sounds.walking.pause();
-        }
+        let wading = self.player.liquid.is_some();
+        keep_playing(&mut self.sounds.walking, self.player.walking && !wading);
+        keep_playing(&mut self.sounds.wading, self.player.walking && wading);
 
         self.sprites
             .iter_mut()
             .for_each(|sprite| sprite.update(time));
 
+        // Closing doors give way to whoever walks into the doorway
+        let blocked: Vec<usize> = self
+            .map
+            .doors
+            .values()
+            .filter(|d| d.closing && self.cell_occupied(d.pos))
+            .map(|d| d.pos)
+            .collect();
         self.map.doors.iter_mut().for_each(|(_, d)| {
-            if d.opening {
-                d.update(dt, &mut self.map.solid)
+            if d.opening || d.closing {
+                let solid = self.map.solid[d.pos];
+                if blocked.contains(&d.pos) {
+                    d.reopen(&mut self.map.solid);
+                }
+                d.update(dt, &mut self.map.solid);
+                // Let light and particles through open doorways
+                if self.map.solid[d.pos] != solid {
+                    self.lighting_1.set_solid(d.pos, self.map.solid[d.pos]);
+                    self.occlusion.set_solid(d.pos, self.map.solid[d.pos]);
+                    self.particles.set_solid(d.pos, self.map.solid[d.pos]);
+                }
             }
         });
 
-        self.torch.update_intensity(time);
+        if let Some(lantern) = self.lantern {
+            self.lighting_1.move_light(lantern, self.player_cell());
+        }
+
+        self.lighting_1.update(time);
+        self.particles.update(dt);
 
         Ok(())
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
         let (w, h) = graphics::drawable_size(ctx);
-        graphics::clear(ctx, Color::MAGENTA);
-        /*let mut corr_angle = self.player.dir_norm.angle();
-        if corr_angle < 0.0 {
-            corr_angle += 2.0 * PI;
-        }
-        let draw_param = graphics::DrawParam {
-            src: graphics::Rect::new(
-                360.0 / FOV * corr_angle / (2.0 * PI),
-                0.4 - self.player.pitch / 864.0,
-                1.0,
-                1.0,
-            ),
-            ..Default::default()
-        };
-        self.sky.sb.set(self.sky.idx, draw_param)?;
-        graphics::draw(ctx, &self.sky.sb, draw_param)?;*/
+        graphics::clear(ctx, Color::BLACK);
 
         (0..h as usize).for_each(|y| {
             // Calculate ceiling y buffer
@@ -439,24 +586,46 @@ impl EventHandler for MainState {
             }*/
         });
 
-        self.sprites
-            .iter_mut()
-            .for_each(|sprite| sprite.set_drawing_bounds(ctx, &self.player, RAYSPERPIXEL as f32));
-
-        self.sprites.sort_by(|a: &Sprite, b: &Sprite| {
-            b.calculate_distance_2(&self.player)
-                .partial_cmp(&a.distance2)
-                .unwrap_or(std::cmp::Ordering::Equal)
+        // Rays go first so sprites and particles know every mirror and portal they show up in
+        let rays: Vec<_> = self
+            .angles
+            .par_iter()
+            .map(|&angle| render::calculate_ray(self, self.player.dir_norm, angle))
+            .collect();
+        let views = Views::collect(&rays);
+
+        self.sprites.iter_mut().for_each(|sprite| {
+            sprite.set_drawing_bounds(
+                ctx,
+                &self.player,
+                RAYSPERPIXEL as f32,
+                &views,
+                &self.map.portals,
+            );
+            sprite.set_lighting(&self.lighting_1, self.map_size);
         });
+        self.particles.project(
+            &self.player,
+            &self.lighting_1,
+            &self.atmosphere,
+            (w, h),
+            RAYSPERPIXEL as f32,
+            &views,
+            &self.map.portals,
+        );
 
         let mut img_arr = std::mem::take(&mut self.screen.img_arr);
+        let mut depth_buffer = std::mem::take(&mut self.depth_buffer);
 
         img_arr
             .par_chunks_mut(h as usize * 4 * RAYSPERPIXEL)
+            .zip(depth_buffer.par_chunks_mut(h as usize))
             .enumerate()
-            .for_each(|(j, slice)| {
-                render::draw_slice(self, slice, w as usize / RAYSPERPIXEL - j - 1, h);
+            .for_each(|(j, (slice, depth))| {
                 let (slice1, slice2) = slice.split_at_mut(h as usize * 4);
+                let j = w as usize / RAYSPERPIXEL - j - 1;
+                let mut column = Column::new(slice1, depth, self.angles[j]);
+                render::draw_slice(self, &mut column, j, h, &rays[j], &views);
                 slice2
                     .chunks_mut(h as usize * 4)
                     .for_each(|sub_slice2| sub_slice2.copy_from_slice(slice1))
@@ -464,7 +633,9 @@ impl EventHandler for MainState {
         /*img_arr.par_chunks_mut(h as usize * 4 * RAYSPERPIXEL). for_each(|slice|{
             let (slice1, slice2) =  slice.split_at_mut(h as usize * 2 * RAYSPERPIXEL);
         slice2.copy_from_slice(&slice1)});*/
+        self.post.apply(&mut img_arr, (w as usize, h as usize));
         self.screen.img_arr = img_arr;
+        self.depth_buffer = depth_buffer;
 
         let img = self.screen.arr_to_rgba(ctx)?;
 
@@ -509,20 +680,33 @@ pub enum Orientation {
     W = 4,
 }
 
-#[allow(dead_code)]
-pub struct Sky {
-    sb: graphics::spritebatch::SpriteBatch,
-    idx: graphics::spritebatch::SpriteIdx,
-}
-
 pub struct Sound {
     walking: audio::Source,
+    wading: audio::Source,
 }
 
 impl Sound {
     pub fn new(ctx: &mut Context) -> GameResult<Self> {
+        // Wading is the footsteps slowed down and heavier
+        let mut wading = audio::Source::new(ctx, "/sounds/walking.ogg")?;
+        wading.set_pitch(0.6);
+        wading.set_volume(0.05);
         Ok(Self {
             walking: audio::Source::new(ctx, "/sounds/walking.ogg")?,
+            wading,
         })
     }
 }
+
+/// Resumes a looping sound while `playing` and pauses it otherwise
+fn keep_playing(source: &mut audio::Source, playing: bool) {
+    if playing {
+        if source.paused() {
+            source.resume();
+        } else if !source.playing() {
+            source.play_later().unwrap();
+        }
+    } else if source.playing() {
+        source.pause();
+    }
+}
@@ -1,56 +1,416 @@
+use ggez::Context;
 use rand::Rng;
 
-use crate::Orientation;
+use crate::{
+    render::Dda,
+    utilities::{datafile, vector2::Vector2},
+    Orientation,
+};
 
 use std::arch::x86_64::*;
 use std::collections::VecDeque;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Dim warm light reaching every cell, about what a torch gives 15 steps away
+const AMBIENT: [f32; 4] = [0.02, 0.015, 0.008, 0.0];
+
+/// Cells reached by a light and how much of it gets to each of them
+type Footprint = Vec<(usize, f32)>;
+
+/// Point light shining from a map cell, `cell` includes the storey offset
+#[derive(Copy, Clone)]
+pub struct Light {
+    pub cell: usize,
+    pub color: [f32; 3],
+    /// Number of steps through open cells the light reaches
+    pub radius: usize,
+    pub flicker: Profile,
+}
+
+/// How the intensity of a light changes over time
+#[derive(Copy, Clone, PartialEq)]
+pub enum Profile {
+    Steady,
+    Torch,
+    Pulse,
+    Strobe,
+    Broken,
+}
+
+impl FromStr for Profile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "steady" => Profile::Steady,
+            "torch" => Profile::Torch,
+            "pulse" => Profile::Pulse,
+            "strobe" => Profile::Strobe,
+            "broken" => Profile::Broken,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Intensity state of one light. Pulses and strobes start at a random phase so lights
+/// sharing a profile don't blink in unison
+pub struct Flicker {
+    profile: Profile,
+    torch: Torch,
+    phase: f32,
+    on: bool,
+    next: f32,
+}
+
+impl Flicker {
+    pub fn new(profile: Profile) -> Self {
+        Self {
+            profile,
+            torch: Torch::default(),
+            phase: rand::thread_rng().gen_range(0.0..1.0),
+            on: true,
+            next: 0.0,
+        }
+    }
+
+    pub fn intensity(&mut self, time: f32) -> f32 {
+        match self.profile {
+            Profile::Steady => 1.0,
+            Profile::Torch => {
+                self.torch.update_intensity(time);
+                self.torch.intensity
+            }
+            Profile::Pulse => {
+                0.75 + 0.25 * (2.0 * std::f32::consts::PI * (0.5 * time + self.phase)).sin()
+            }
+            Profile::Strobe => {
+                if (4.0 * time + self.phase).fract() < 0.5 {
+                    1.0
+                } else {
+                    0.1
+                }
+            }
+            Profile::Broken => {
+                // Mostly on with short dropouts, like a dying fluorescent tube
+                if time > self.next {
+                    self.on = !self.on;
+                    self.next = time
+                        + if self.on {
+                            rand::thread_rng().gen_range(0.05..2.0)
+                        } else {
+                            rand::thread_rng().gen_range(0.02..0.2)
+                        };
+                }
+                if self.on {
+                    1.0
+                } else {
+                    0.15
+                }
+            }
+        }
+    }
+}
+
+/// A light along with the cells it reaches, how much of it gets there and how bright it
+/// currently is
+struct LitLight {
+    light: Light,
+    footprint: Vec<(usize, f32)>,
+    flicker: Flicker,
+    intensity: f32,
+}
+
+impl LitLight {
+    fn new(light: Light) -> Self {
+        Self {
+            light,
+            footprint: vec![],
+            flicker: Flicker::new(light.flicker),
+            intensity: 1.0,
+        }
+    }
+}
 
 pub struct Lighting {
     vertices: Vec<Vertex>,
-    lighting: Vec<f32>,
+    lighting: Vec<[f32; 4]>,
+    /// Sum of the directions towards the lights reaching each cell weighted by their
+    /// brightness, the weight total is the last element
+    directions: Vec<[f32; 4]>,
+    /// Solid cells of both storeys, kept in sync with the map through `set_solid`
+    solid: Vec<bool>,
+    lights: Vec<Option<LitLight>>,
+    /// Lights reaching each cell and their falloff there, the light of a cell is rebuilt from
+    /// them whenever one of them changes
+    reach: Vec<Vec<(usize, f32)>>,
+    /// Cells waiting to be rebuilt, `marked` keeps them from being queued twice
+    dirty: Vec<usize>,
+    /// Cells whose corners are waiting to be recomputed
+    corners: Vec<usize>,
+    marked: Vec<bool>,
     map_size: (usize, usize),
+    /// Lights only reach what they can see instead of flowing around corners
+    shadows: bool,
+    /// Footprints of the level's lights from the bake, flood filled and ray traced, empty
+    /// without one
+    baked: [Vec<Footprint>; 2],
+    /// Hash of the lights and the map the bake was made from
+    baked_hash: u64,
     pub switch: bool,
     pub smooth_switch: bool,
 }
 
 impl Lighting {
-    pub fn new(torches_pos: Vec<usize>, map: &[bool], map_size: (usize, usize)) -> Self {
-        let light_int = calculate_lighting(torches_pos, map, map_size);
-        let lighting: Vec<f32> = light_int
+    pub fn new(lights: &[Light], map: &[bool], map_size: (usize, usize)) -> Self {
+        let cells = map_size.0 * map_size.1 * 2;
+        let mut lighting = Self {
+            vertices: vec![Vertex::default(); cells * 4],
+            lighting: vec![AMBIENT; cells],
+            directions: vec![[0.0; 4]; cells],
+            solid: [map, map].concat(),
+            lights: vec![],
+            reach: vec![vec![]; cells],
+            dirty: vec![],
+            corners: vec![],
+            marked: vec![false; cells],
+            map_size,
+            shadows: false,
+            baked: [vec![], vec![]],
+            baked_hash: 0,
+            switch: true,
+            smooth_switch: true,
+        };
+        (0..cells).for_each(|cell| lighting.mark(cell));
+        lighting.relight();
+        lights.iter().for_each(|light| {
+            lighting.add_light(*light);
+        });
+        lighting
+    }
+
+    /// Builds the lighting from the footprints baked at `path` by `bake`. Without a bake made
+    /// for these lights and this map the lights are spread while loading instead
+    pub fn load(
+        ctx: &mut Context,
+        path: &Path,
+        lights: &[Light],
+        map: &[bool],
+        map_size: (usize, usize),
+    ) -> Self {
+        let mut lighting = Self::new(&[], map, map_size);
+        let hash = bake_hash(lights, &lighting.solid);
+        let entries = datafile::read(ctx, path).unwrap_or_default();
+        match read_bake(&entries, hash, lights.len(), lighting.solid.len()) {
+            Some(baked) => {
+                lighting.baked = baked;
+                lighting.baked_hash = hash;
+                for (id, light) in lights.iter().enumerate() {
+                    let mut lit = LitLight::new(*light);
+                    lit.footprint = lighting.baked[lighting.shadows as usize][id].clone();
+                    lighting.lights.push(None);
+                    lighting.shine(id, lit);
+                }
+            }
+            None => lights.iter().for_each(|light| {
+                lighting.add_light(*light);
+            }),
+        }
+        lighting
+    }
+
+    /// Whether the level's lights and the map are still the ones the bake was made from
+    fn bake_matches(&self) -> bool {
+        let count = self.baked[0].len();
+        let lights: Option<Vec<Light>> = self.lights[..count.min(self.lights.len())]
             .iter()
-            .map(|l| 0.7f32.powf(0.8 * (15 - l) as f32) / (127.0 * 127.0))
+            .map(|lit| lit.as_ref().map(|lit| lit.light))
             .collect();
-        let mut all_vertices = vec![];
-        for k in 0..2 {
-            for j in 0..map_size.1 + 1 {
-                for i in 0..map_size.0 + 1 {
-                    all_vertices.push(Vertex::new([i, j, k], map_size, &lighting));
+        match lights {
+            Some(lights) => {
+                count > 0
+                    && lights.len() == count
+                    && bake_hash(&lights, &self.solid) == self.baked_hash
+            }
+            None => false,
+        }
+    }
+
+    /// Lights up the cells reached by a new light, the returned id moves or removes it
+    pub fn add_light(&mut self, light: Light) -> usize {
+        let id = self
+            .lights
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.lights.len());
+        if id == self.lights.len() {
+            self.lights.push(None);
+        }
+        self.light_up(id, LitLight::new(light));
+        id
+    }
+
+    pub fn remove_light(&mut self, id: usize) {
+        self.take(id);
+    }
+
+    pub fn move_light(&mut self, id: usize, cell: usize) {
+        if let Some(Some(lit)) = self.lights.get(id) {
+            if lit.light.cell != cell {
+                if let Some(mut lit) = self.take(id) {
+                    lit.light.cell = cell;
+                    self.light_up(id, lit);
                 }
             }
         }
+    }
 
-        let mut vertices = vec![Vertex::default(); map_size.0 * map_size.1 * 4 * 2];
+    /// Changes the solidity of a map cell on both storeys, only the lights that reach the
+    /// cell or one next to it are spread again
+    pub fn set_solid(&mut self, cell: usize, solid: bool) {
+        let layer = self.map_size.0 * self.map_size.1;
+        for cell in [cell, cell + layer] {
+            if self.solid[cell] == solid {
+                continue;
+            }
+            self.solid[cell] = solid;
+            let mut near = neighbors(cell, self.map_size).to_vec();
+            near.push(Some(cell));
+            for id in 0..self.lights.len() {
+                let touched = match &self.lights[id] {
+                    Some(lit) => lit.footprint.iter().any(|(c, _)| near.contains(&Some(*c))),
+                    None => false,
+                };
+                if touched {
+                    if let Some(lit) = self.take(id) {
+                        self.light_up(id, lit);
+                    }
+                }
+            }
+        }
+    }
 
-        vertices.chunks_mut(4).enumerate().for_each(|(pos, chunk)| {
-            let z = pos / (map_size.0 * map_size.1);
-            let offset = (pos / map_size.0) + z * (map_size.0 + 1);
-            let tl = all_vertices[pos + offset];
-            let tr = all_vertices[pos + 1 + offset];
-            let bl = all_vertices[pos + map_size.0 + 1 + offset];
-            let br = all_vertices[pos + map_size.0 + 2 + offset];
-            chunk.copy_from_slice(&[tl, tr, bl, br]);
-        });
+    pub fn shadows(&self) -> bool {
+        self.shadows
+    }
 
-        Self {
-            vertices,
-            lighting,
-            map_size,
-            switch: true,
-            smooth_switch: true,
+    /// Switches between flood filled and ray traced lighting, spreading every light again.
+    /// The level's lights come from the bake while no door has changed the map
+    pub fn set_shadows(&mut self, shadows: bool) {
+        self.shadows = shadows;
+        let baked = self.bake_matches();
+        for id in 0..self.lights.len() {
+            if let Some(mut lit) = self.take(id) {
+                match self.baked[shadows as usize].get(id).filter(|_| baked) {
+                    Some(footprint) => {
+                        lit.footprint = footprint.clone();
+                        self.shine(id, lit);
+                    }
+                    None => self.light_up(id, lit),
+                }
+            }
         }
     }
+
+    /// Advances the flicker of every light and relights the cells of those that changed
+    pub fn update(&mut self, time: f32) {
+        for lit in self.lights.iter_mut().flatten() {
+            let intensity = lit.flicker.intensity(time);
+            if intensity == lit.intensity {
+                continue;
+            }
+            lit.intensity = intensity;
+            for &(cell, _) in &lit.footprint {
+                if !self.marked[cell] {
+                    self.marked[cell] = true;
+                    self.dirty.push(cell);
+                }
+            }
+        }
+        self.relight();
+    }
+
+    /// Removes a light from the grid
+    fn take(&mut self, id: usize) -> Option<LitLight> {
+        let lit = self.lights.get_mut(id).and_then(Option::take)?;
+        for &(cell, _) in &lit.footprint {
+            self.reach[cell].retain(|&(light, _)| light != id);
+            self.mark(cell);
+        }
+        self.relight();
+        Some(lit)
+    }
+
+    fn light_up(&mut self, id: usize, mut lit: LitLight) {
+        lit.footprint = footprint(&lit.light, &self.solid, self.map_size, self.shadows);
+        self.shine(id, lit);
+    }
+
+    /// Puts a light whose footprint is known into its slot
+    fn shine(&mut self, id: usize, lit: LitLight) {
+        for &(cell, falloff) in &lit.footprint {
+            self.reach[cell].push((id, falloff));
+            self.mark(cell);
+        }
+        self.lights[id] = Some(lit);
+        self.relight();
+    }
+
+    /// Queues a cell to be rebuilt by the next `relight`
+    fn mark(&mut self, cell: usize) {
+        if !self.marked[cell] {
+            self.marked[cell] = true;
+            self.dirty.push(cell);
+        }
+    }
+
+    /// Rebuilds the queued cells from the current intensity of the lights reaching them, then
+    /// the corners of those cells and of the cells sharing them. Starting over from the
+    /// lights keeps rounding errors from piling up
+    fn relight(&mut self) {
+        let (w, h) = self.map_size;
+        for &cell in &self.dirty {
+            self.marked[cell] = false;
+            self.lighting[cell] = AMBIENT;
+            self.directions[cell] = [0.0; 4];
+            for &(id, falloff) in &self.reach[cell] {
+                if let Some(lit) = &self.lights[id] {
+                    contribute(
+                        &mut self.lighting[cell],
+                        &mut self.directions[cell],
+                        &lit.light,
+                        cell,
+                        lit.intensity * falloff,
+                        self.map_size,
+                    );
+                }
+            }
+        }
+        for &cell in &self.dirty {
+            let (x, y, z) = (cell % w, (cell / w) % h, cell / (w * h));
+            for j in y.saturating_sub(1)..(y + 2).min(h) {
+                for i in x.saturating_sub(1)..(x + 2).min(w) {
+                    let pos = i + j * w + z * w * h;
+                    if !self.marked[pos] {
+                        self.marked[pos] = true;
+                        self.corners.push(pos);
+                    }
+                }
+            }
+        }
+        self.dirty.clear();
+        for &pos in &self.corners {
+            self.marked[pos] = false;
+            let (x, y, z) = (pos % w, (pos / w) % h, pos / (w * h));
+            let corners = [[x, y], [x + 1, y], [x, y + 1], [x + 1, y + 1]];
+            for (k, [i, j]) in corners.into_iter().enumerate() {
+                self.vertices[pos * 4 + k] = Vertex::new([i, j, z], self.map_size, &self.lighting);
+            }
+        }
+        self.corners.clear();
+    }
     #[inline(always)]
-    pub fn get_lighting_floor(&self, x: f32, y: f32, pos: usize) -> f32 {
+    pub fn get_lighting_floor(&self, x: f32, y: f32, pos: usize) -> [f32; 4] {
         if self.switch {
             let (tl, tr, bl, br) = get_vertices(pos, &self.vertices);
             if self.smooth_switch {
@@ -65,10 +425,10 @@ impl Lighting {
                     )
                 }
             } else {
-                127.0 * 127.0 * self.lighting[pos]
+                self.lighting[pos]
             }
         } else {
-            1.0
+            [1.0; 4]
         }
     }
 
@@ -80,7 +440,7 @@ impl Lighting {
         pos: usize,
         orientation: &Orientation,
         is_up: bool,
-    ) -> f32 {
+    ) -> [f32; 4] {
         if self.switch {
             if self.smooth_switch {
                 if !is_up {
@@ -271,123 +631,329 @@ impl Lighting {
                     }
                 }
             } else {
-                let location = match orientation {
-                    Orientation::N => pos - self.map_size.0,
-                    Orientation::S => pos + self.map_size.0,
-                    Orientation::E => pos - 1,
-                    Orientation::W => pos + 1,
-                };
-
-                127.0 * 127.0 * self.lighting[location]
+                self.lighting[self.facing_cell(pos, orientation)]
             }
         } else {
-            1.0
+            [1.0; 4]
+        }
+    }
+
+    /// Open cell in front of a wall face, whose light the face gets
+    #[inline(always)]
+    fn facing_cell(&self, pos: usize, orientation: &Orientation) -> usize {
+        match orientation {
+            Orientation::N => pos - self.map_size.0,
+            Orientation::S => pos + self.map_size.0,
+            Orientation::E => pos - 1,
+            Orientation::W => pos + 1,
+        }
+    }
+
+    /// Mean direction towards the lights reaching a cell, shorter the more evenly they
+    /// surround it. Zero when lighting is off so normal maps stay flat
+    #[inline(always)]
+    pub fn get_direction_floor(&self, pos: usize) -> [f32; 3] {
+        let [x, y, z, weight] = self.directions[pos];
+        if self.switch && weight > 1e-4 {
+            [x / weight, y / weight, z / weight]
+        } else {
+            [0.0; 3]
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_direction_wall(&self, pos: usize, orientation: &Orientation) -> [f32; 3] {
+        self.get_direction_floor(self.facing_cell(pos, orientation))
+    }
+}
+
+/// Blends `amount` of a light into a cell along with the direction it comes from. Lights
+/// are screened over each other, so every one of them shows while overlapping torches never
+/// go past full brightness. Lights shine from the middle of their cell, onto the floor of
+/// their own
+#[inline(always)]
+fn contribute(
+    lighting: &mut [f32; 4],
+    direction: &mut [f32; 4],
+    light: &Light,
+    cell: usize,
+    amount: f32,
+    map_size: (usize, usize),
+) {
+    for (l, c) in lighting.iter_mut().zip(light.color) {
+        *l = screen(*l, amount * c);
+    }
+    let (w, h) = map_size;
+    let position = |cell: usize| [cell % w, (cell / w) % h, cell / (w * h)].map(|c| c as f32);
+    let (from, to) = (position(cell), position(light.cell));
+    let mut towards = [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+    let length = towards.iter().map(|t| t * t).sum::<f32>().sqrt();
+    if length == 0.0 {
+        towards = [0.0, 0.0, 1.0];
+    } else {
+        towards = towards.map(|t| t / length);
+    }
+    let weight = amount * light.color.iter().sum::<f32>() / 3.0;
+    for (d, t) in direction.iter_mut().zip(towards) {
+        *d += weight * t;
+    }
+    direction[3] += weight;
+}
+
+/// Soft maximum of two light levels, as bright as the brighter one for a dark other one and
+/// never past 1
+#[inline(always)]
+fn screen(a: f32, b: f32) -> f32 {
+    1.0 - (1.0 - a) * (1.0 - b.clamp(0.0, 1.0))
+}
+
+/// Footprints of the lights both flood filled and ray traced, with the hash of the lights and
+/// the map they were spread from. `map` is the ground storey like for `Lighting::new`
+pub fn bake(lights: &[Light], map: &[bool], map_size: (usize, usize)) -> String {
+    let solid = [map, map].concat();
+    let mut text = format!(
+        "# Light footprints baked by running the game with --bake, one line of cell falloff\n\
+         # pairs per light. Bake again after changing the lights or the walls of the level\n\
+         hash={}\n",
+        bake_hash(lights, &solid)
+    );
+    for (section, shadows) in [("flood", false), ("shadows", true)] {
+        text += &format!("[{}]\n", section);
+        for light in lights {
+            let pairs: Vec<String> = footprint(light, &solid, map_size, shadows)
+                .iter()
+                .map(|(cell, falloff)| format!("{} {}", cell, falloff))
+                .collect();
+            text += &pairs.join(" ");
+            text.push('\n');
         }
     }
+    text
+}
+
+/// FNV-1a hash of everything the footprints depend on, so a bake made for a different
+/// level or lights is never used
+fn bake_hash(lights: &[Light], solid: &[bool]) -> u64 {
+    let mut bytes: Vec<u8> = solid.iter().map(|&s| s as u8).collect();
+    for light in lights {
+        bytes.extend((light.cell as u64).to_le_bytes());
+        bytes.extend((light.radius as u64).to_le_bytes());
+    }
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
-pub fn calculate_lighting(
-    torches_pos: Vec<usize>,
+/// Flood filled and ray traced footprints of a bake made with `hash`, `None` if there is no
+/// such bake or it is damaged
+fn read_bake(
+    entries: &[datafile::Entry],
+    hash: u64,
+    lights: usize,
+    cells: usize,
+) -> Option<[Vec<Footprint>; 2]> {
+    let baked = entries
+        .iter()
+        .find(|entry| entry.section.is_empty())?
+        .option::<u64>("hash")
+        .ok()??;
+    if baked != hash {
+        return None;
+    }
+    let footprints = |section: &str| {
+        entries
+            .iter()
+            .filter(|entry| entry.section == section)
+            .map(|entry| {
+                let fields: Vec<&str> = (0..).map_while(|i| entry.name(i).ok()).collect();
+                fields
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [cell, falloff] => Some((cell.parse().ok()?, falloff.parse().ok()?)),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<(usize, f32)>>>()
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+    let baked = [footprints("flood")?, footprints("shadows")?];
+    let valid = baked.iter().all(|footprints| {
+        footprints.len() == lights && footprints.iter().flatten().all(|&(cell, _)| cell < cells)
+    });
+    valid.then_some(baked)
+}
+
+/// Cells reached by a light, ray traced with `shadows` and flood filled without
+fn footprint(
+    light: &Light,
     map: &[bool],
     map_size: (usize, usize),
-) -> Vec<u8> {
-    let map = [map, map].concat();
+    shadows: bool,
+) -> Vec<(usize, f32)> {
+    if shadows {
+        cast(light, map, map_size)
+    } else {
+        spread(light, map, map_size)
+    }
+}
+
+/// Cells reached by a light and its falloff in each of them. The light spreads through open
+/// cells and up or down between storeys, dimming with every step
+fn spread(light: &Light, map: &[bool], map_size: (usize, usize)) -> Vec<(usize, f32)> {
+    let mut steps = vec![usize::MAX; map.len()];
     let mut lightq = VecDeque::new();
-    let mut light_int: Vec<u8> = vec![0; map_size.0 * map_size.1 * 2];
-    torches_pos.into_iter().for_each(|light_pos| {
-        lightq.push_front(light_pos);
-        light_int[light_pos] = 15;
-    });
+    let mut footprint = vec![];
+    steps[light.cell] = 0;
+    lightq.push_back(light.cell);
 
-    while !lightq.is_empty() {
-        let node = *lightq.front().expect("Queue is empty");
-        lightq.pop_front();
-        let x = node % (map_size.0);
-        let y = (node / map_size.0) % map_size.1;
-        let z = node / (map_size.0 * map_size.1);
-        let light_node = light_int[node];
-
-        //negative x neighbor
-        if x > 0 {
-            let neighbor = x - 1 + y * map_size.0 + z * map_size.0 * map_size.1;
-            if !map[neighbor]
-                && light_int[neighbor] <= light_node - 2
-                && light_node != 0
-                && light_node != 1
-            {
-                light_int[neighbor] = light_node - 1;
-                lightq.push_back(neighbor);
-            }
+    while let Some(node) = lightq.pop_front() {
+        let step = steps[node];
+        footprint.push((node, 0.7f32.powf(0.8 * step as f32)));
+        if step + 1 >= light.radius {
+            continue;
         }
-
-        //Positive x neighbor
-        if x < map_size.0 - 1 {
-            let neighbor = x + 1 + y * map_size.0 + z * map_size.0 * map_size.1;
-            if !map[neighbor] && light_int[neighbor] <= light_node - 2 && light_node != 1 {
-                light_int[neighbor] = light_node - 1;
+        for neighbor in neighbors(node, map_size).into_iter().flatten() {
+            if !map[neighbor] && steps[neighbor] == usize::MAX {
+                steps[neighbor] = step + 1;
                 lightq.push_back(neighbor);
             }
         }
+    }
+    footprint
+}
 
-        //negative y neighbor
-        if y > 0 {
-            let neighbor = x + (y - 1) * map_size.0 + z * map_size.0 * map_size.1;
-            if !map[neighbor] && light_int[neighbor] <= light_node - 2 && light_node != 1 {
-                light_int[neighbor] = light_node - 1;
-                lightq.push_back(neighbor);
+/// Cells within a light's radius that it can see and how much of it gets there. A light
+/// shines from the middle of its cell and dims with the straight distance, cells above or
+/// below it are one unit away
+fn cast(light: &Light, map: &[bool], map_size: (usize, usize)) -> Vec<(usize, f32)> {
+    let (w, h) = map_size;
+    let (lx, ly, lz) = (light.cell % w, (light.cell / w) % h, light.cell / (w * h));
+    let from = [lx as f32 + 0.5, ly as f32 + 0.5];
+    let mut footprint = vec![(light.cell, 1.0)];
+    for z in 0..2 {
+        for y in ly.saturating_sub(light.radius)..(ly + light.radius + 1).min(h) {
+            for x in lx.saturating_sub(light.radius)..(lx + light.radius + 1).min(w) {
+                let cell = x + y * w + z * w * h;
+                if cell == light.cell || map[cell] {
+                    continue;
+                }
+                let (dx, dy, dz) = (
+                    x as f32 - lx as f32,
+                    y as f32 - ly as f32,
+                    z as f32 - lz as f32,
+                );
+                let distance = (dx * dx + dy * dy + dz * dz).sqrt();
+                if distance >= light.radius as f32 {
+                    continue;
+                }
+                let visible = visibility(from, [x, y], map, map_size);
+                if visible > 0.0 {
+                    footprint.push((cell, visible * 0.7f32.powf(0.8 * distance)));
+                }
             }
         }
+    }
+    footprint
+}
 
-        //Positive y neighbor
-        if y < map_size.1 - 1 {
-            let neighbor = x + (y + 1) * map_size.0 + z * map_size.0 * map_size.1;
-            if !map[neighbor] && light_int[neighbor] <= light_node - 2 && light_node != 1 {
-                light_int[neighbor] = light_node - 1;
-                lightq.push_back(neighbor);
-            }
-        }
-        // up neighbor
-        if z > 0 {
-            let neighbor = x + y * map_size.0 + (z - 1) * map_size.0 * map_size.1;
-            if !map[neighbor] && light_int[neighbor] <= light_node - 2 && light_node != 1 {
-                light_int[neighbor] = light_node - 1;
-                lightq.push_back(neighbor);
-            }
+/// Share of the middle and the corners of a cell that can be seen from `from`
+fn visibility(from: [f32; 2], cell: [usize; 2], map: &[bool], map_size: (usize, usize)) -> f32 {
+    let (x, y) = (cell[0] as f32, cell[1] as f32);
+    let points = [
+        [x + 0.5, y + 0.5],
+        [x + 0.05, y + 0.05],
+        [x + 0.95, y + 0.05],
+        [x + 0.05, y + 0.95],
+        [x + 0.95, y + 0.95],
+    ];
+    let seen = points
+        .iter()
+        .filter(|&&to| line_of_sight(from, to, map, map_size))
+        .count();
+    seen as f32 / points.len() as f32
+}
+
+/// Whether the cells between two points are all open, using the same grid walk as the
+/// rendering rays
+fn line_of_sight(from: [f32; 2], to: [f32; 2], map: &[bool], map_size: (usize, usize)) -> bool {
+    let delta = Vector2::new(to[0] - from[0], to[1] - from[1]);
+    let length = delta.magnitude();
+    let target = [to[0].floor(), to[1].floor()];
+    if length == 0.0 {
+        return true;
+    }
+    let mut dda = Dda::new(Vector2::new(from[0], from[1]), delta * (1.0 / length));
+    loop {
+        let (distance, _) = dda.step();
+        let cell = dda.map_checkv;
+        if distance >= length || (cell.x == target[0] && cell.y == target[1]) {
+            return true;
         }
-        // down neighbor
-        if z < 1 {
-            let neighbor = x + y * map_size.0 + (z + 1) * map_size.0 * map_size.1;
-            if !map[neighbor] && light_int[neighbor] <= light_node - 2 && light_node != 1 {
-                light_int[neighbor] = light_node - 1;
-                lightq.push_back(neighbor);
-            }
+        if cell.x < 0.0
+            || cell.y < 0.0
+            || cell.x >= map_size.0 as f32
+            || cell.y >= map_size.1 as f32
+            || map[cell.x as usize + cell.y as usize * map_size.0]
+        {
+            return false;
         }
     }
-    light_int
 }
+
+/// Cells next to a cell on its storey and the cells above and below it
+fn neighbors(node: usize, map_size: (usize, usize)) -> [Option<usize>; 6] {
+    let layer = map_size.0 * map_size.1;
+    let x = node % map_size.0;
+    let y = (node / map_size.0) % map_size.1;
+    let z = node / layer;
+    [
+        (x > 0).then(|| node - 1),
+        (x < map_size.0 - 1).then(|| node + 1),
+        (y > 0).then(|| node - map_size.0),
+        (y < map_size.1 - 1).then(|| node + map_size.0),
+        (z > 0).then(|| node - layer),
+        (z < 1).then(|| node + layer),
+    ]
+}
+
+/// Interpolates the four corner colors, x and y go from 0 to 127 across the cell
 #[inline(always)]
-unsafe fn bilerp(x: f32, y: f32, v1: f32, v2: f32, v3: f32, v4: f32) -> f32 {
+unsafe fn bilerp(
+    x: f32,
+    y: f32,
+    v1: [f32; 4],
+    v2: [f32; 4],
+    v3: [f32; 4],
+    v4: [f32; 4],
+) -> [f32; 4] {
     let x2 = 127.0 - x;
     let y2 = 127.0 - y;
+    let norm = 1.0 / (127.0 * 127.0);
 
-    let v_a1 = _mm_set_ps(v4, v3, v2, v1); //[bottom left,bottom right, top left, top right]
-    let v_a2 = _mm_set_ps(x, y, x, x2);
-    let v_a3 = _mm_set_ps(y, x2, y2, y2);
-
-    let m = _mm_mul_ps(v_a1, _mm_mul_ps(v_a2, v_a3));
-    let t1 = _mm_hadd_ps(m, m);
-    _mm_cvtss_f32(_mm_hadd_ps(t1, t1))
+    let top = _mm_add_ps(
+        _mm_mul_ps(_mm_loadu_ps(v1.as_ptr()), _mm_set1_ps(x2 * y2 * norm)),
+        _mm_mul_ps(_mm_loadu_ps(v2.as_ptr()), _mm_set1_ps(x * y2 * norm)),
+    );
+    let bottom = _mm_add_ps(
+        _mm_mul_ps(_mm_loadu_ps(v3.as_ptr()), _mm_set1_ps(x2 * y * norm)),
+        _mm_mul_ps(_mm_loadu_ps(v4.as_ptr()), _mm_set1_ps(x * y * norm)),
+    );
+    let mut out = [0.0; 4];
+    _mm_storeu_ps(out.as_mut_ptr(), _mm_add_ps(top, bottom));
+    out
 }
 
 #[inline(always)]
-unsafe fn lerp(x: f32, l: f32, r: f32) -> f32 {
+unsafe fn lerp(x: f32, l: [f32; 4], r: [f32; 4]) -> [f32; 4] {
     let x2 = 127.0 - x;
-    let v_a1 = _mm_set_ps(0.0, 0.0, r, l);
-    let v_a2 = _mm_set_ps(0.0, 0.0, x, x2);
-    let t1 = _mm_mul_ps(v_a1, v_a2);
-    _mm_cvtss_f32(_mm_hadd_ps(t1, t1)) * 127.0
-
-    //(l * x2 + x * r) * 127.0
+    let norm = 1.0 / 127.0;
+    let m = _mm_add_ps(
+        _mm_mul_ps(_mm_loadu_ps(l.as_ptr()), _mm_set1_ps(x2 * norm)),
+        _mm_mul_ps(_mm_loadu_ps(r.as_ptr()), _mm_set1_ps(x * norm)),
+    );
+    let mut out = [0.0; 4];
+    _mm_storeu_ps(out.as_mut_ptr(), m);
+    out
 }
 
 #[inline(always)]
@@ -398,61 +964,35 @@ fn get_vertices(pos: usize, vertices: &[Vertex]) -> (Vertex, Vertex, Vertex, Ver
     let br = vertices[pos * 4 + 3];
     (tl, tr, bl, br)
 }
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct Vertex {
-    lighting: f32,
+    lighting: [f32; 4],
 }
 
 impl Vertex {
-    pub fn new(pos: [usize; 3], map_size: (usize, usize), lighting: &[f32]) -> Self {
+    /// Average of the cells around a cell corner, cells outside of the map are dark
+    pub fn new(pos: [usize; 3], map_size: (usize, usize), lighting: &[[f32; 4]]) -> Self {
         let x = pos[0];
         let y = pos[1];
         let z = pos[2];
-        let neighbor1 = {
-            if x > 0 && y < map_size.1 {
-                lighting[x - 1 + map_size.0 * y + z * map_size.0 * map_size.1]
-            } else {
-                0.0
-            }
-        };
-        let neighbor2 = {
-            if x < map_size.0 && y < map_size.1 {
-                lighting[x + map_size.0 * y + z * map_size.0 * map_size.1]
-            } else {
-                0.0
-            }
-        };
-        let neighbor3 = {
-            if y > 0 && x < map_size.0 {
-                lighting[x + map_size.0 * (y - 1) + z * map_size.0 * map_size.1]
-            } else {
-                0.0
-            }
-        };
-        let neighbor4 = {
-            if y > 0 && x > 0 {
-                lighting[x - 1 + map_size.0 * (y - 1) + z * map_size.0 * map_size.1]
-            } else {
-                0.0
+        let cell = |x: usize, y: usize| lighting[x + map_size.0 * y + z * map_size.0 * map_size.1];
+        let neighbors = [
+            (x > 0 && y < map_size.1).then(|| cell(x - 1, y)),
+            (x < map_size.0 && y < map_size.1).then(|| cell(x, y)),
+            (y > 0 && x < map_size.0).then(|| cell(x, y - 1)),
+            (y > 0 && x > 0).then(|| cell(x - 1, y - 1)),
+        ];
+        let mut lighting = [0.0; 4];
+        for neighbor in neighbors.into_iter().flatten() {
+            for (l, n) in lighting.iter_mut().zip(neighbor) {
+                *l += n / 4.0;
             }
-        };
-
-        let lighting = num::clamp(
-            (neighbor1 + neighbor2 + neighbor3 + neighbor4) / 4.0,
-            0.0,
-            1.0,
-        );
+        }
 
         Self { lighting }
     }
 }
 
-impl Default for Vertex {
-    fn default() -> Self {
-        Self { lighting: 0.0 }
-    }
-}
-
 pub struct Torch {
     pub intensity: f32,
     flicker_r_low: f32,
@@ -523,3 +1063,182 @@ impl Default for Torch {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn directions_point_at_the_lights() {
+        let map = [false; 3];
+        let light = |cell| Light {
+            cell,
+            color: [1.0; 3],
+            radius: 15,
+            flicker: Profile::Steady,
+        };
+        let mut lighting = Lighting::new(&[light(0)], &map, (3, 1));
+        assert_eq!(lighting.get_direction_floor(1), [-1.0, 0.0, 0.0]);
+        assert_eq!(lighting.get_direction_floor(0), [0.0, 0.0, 1.0]);
+        // Lights on both sides cancel out
+        lighting.add_light(light(2));
+        assert!(lighting.get_direction_floor(1)[0].abs() < 1e-6);
+        lighting.switch = false;
+        assert_eq!(lighting.get_direction_floor(0), [0.0; 3]);
+    }
+
+    #[test]
+    fn bakes_round_trip_until_the_map_changes() {
+        let map = [false, false, true, false];
+        let light = Light {
+            cell: 0,
+            color: [1.0, 0.5, 0.0],
+            radius: 15,
+            flicker: Profile::Steady,
+        };
+        let mut lighting = Lighting::new(&[light], &map, (4, 1));
+        let entries = datafile::parse(&bake(&[light], &map, (4, 1)));
+        let hash = bake_hash(&[light], &lighting.solid);
+        let baked = read_bake(&entries, hash, 1, 8).unwrap();
+        assert_eq!(baked[0][0], lighting.lights[0].as_ref().unwrap().footprint);
+        assert_eq!(baked[1][0], cast(&light, &lighting.solid, (4, 1)));
+
+        let opened = [false; 8];
+        assert!(read_bake(&entries, bake_hash(&[light], &opened), 1, 8).is_none());
+        assert!(read_bake(&entries, hash, 2, 8).is_none());
+
+        // Switching to shadows uses the bake until the map changes
+        (lighting.baked, lighting.baked_hash) = (baked, hash);
+        assert!(lighting.bake_matches());
+        lighting.set_solid(2, false);
+        assert!(!lighting.bake_matches());
+    }
+
+    /// Level of a channel lit by a light reaching it with `amount`
+    fn lit(channel: usize, amount: f32) -> f32 {
+        screen(AMBIENT[channel], amount)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn colored_lights_mix() {
+        // 4x1 corridor with a wall in the third cell
+        let map = [false, false, true, false];
+        let red = Light {
+            cell: 0,
+            color: [1.0, 0.0, 0.0],
+            radius: 15,
+            flicker: Profile::Steady,
+        };
+        let blue = Light {
+            cell: 1,
+            color: [0.0, 0.0, 1.0],
+            radius: 1,
+            flicker: Profile::Steady,
+        };
+        let mut lighting = Lighting::new(&[red, blue], &map, (4, 1));
+        assert_eq!(lighting.lighting[0][0], 1.0);
+        assert!(close(lighting.lighting[1][0], lit(0, 0.7f32.powf(0.8))));
+        // The blue light doesn't leave its cell
+        assert_eq!(lighting.lighting[1][2], 1.0);
+        assert!(close(lighting.lighting[0][2], AMBIENT[2]));
+        // Nothing gets past the wall, the upper storey is one step above
+        assert!(close(lighting.lighting[3][0], AMBIENT[0]));
+        assert!(close(lighting.lighting[4][0], lit(0, 0.7f32.powf(0.8))));
+
+        // Opening the wall lets the red light through, removing it leaves the blue one
+        lighting.set_solid(2, false);
+        assert!(close(lighting.lighting[3][0], lit(0, 0.7f32.powf(2.4))));
+        lighting.remove_light(0);
+        assert!(close(lighting.lighting[3][0], AMBIENT[0]));
+        assert_eq!(lighting.lighting[1][2], 1.0);
+
+        // Freed ids are reused, a moved light no longer shines on its old cell
+        let id = lighting.add_light(blue);
+        assert_eq!(id, 0);
+        lighting.move_light(id, 3);
+        assert_eq!(lighting.lighting[3][2], 1.0);
+        assert_eq!(lighting.lighting[1][2], 1.0);
+
+        // Overlapping lights of a color are brighter than either but never past full
+        let single = lighting.lighting[2][0];
+        lighting.add_light(Light { cell: 3, ..red });
+        let both = lighting.lighting[2][0];
+        assert!(both > single && both < 1.0);
+    }
+
+    #[test]
+    fn pillars_cast_shadows() {
+        // 5x3 room with a pillar in the middle and a light left of it
+        let mut map = vec![false; 15];
+        map[7] = true;
+        let light = Light {
+            cell: 5,
+            color: [1.0, 1.0, 1.0],
+            radius: 15,
+            flicker: Profile::Steady,
+        };
+        let mut lighting = Lighting::new(&[light], &map, (5, 3));
+        // Light flows around the pillar until shadows are on
+        assert!(lighting.lighting[8][0] > AMBIENT[0] + 1e-6);
+        lighting.set_shadows(true);
+        assert!(close(lighting.lighting[8][0], AMBIENT[0]));
+        assert!(close(lighting.lighting[6][0], lit(0, 0.7f32.powf(0.8))));
+        // Partly hidden cells get part of the light
+        let corner = lighting.lighting[4][0];
+        assert!(corner > AMBIENT[0] + 1e-6 && corner < lit(0, 0.7f32.powf(0.8 * 17f32.sqrt())));
+    }
+
+    #[test]
+    fn flicker_scales_contribution() {
+        let strobe = Light {
+            cell: 0,
+            color: [1.0, 1.0, 1.0],
+            radius: 2,
+            flicker: Profile::Strobe,
+        };
+        let mut lighting = Lighting::new(&[strobe], &[false, false], (2, 1));
+        let phase = lighting.lights[0].as_ref().unwrap().flicker.phase;
+        // Half way through the dark part of the strobe
+        lighting.update((0.75 - phase).rem_euclid(1.0) / 4.0);
+        assert!(close(lighting.lighting[0][0], lit(0, 0.1)));
+        assert!(close(
+            lighting.lighting[1][0],
+            lit(0, 0.1 * 0.7f32.powf(0.8))
+        ));
+        // Cells are rebuilt from the lights, so blinking for a long time leaves nothing behind
+        for i in 0..10000 {
+            lighting.update(i as f32 * 0.013);
+        }
+        lighting.update((0.25 - phase).rem_euclid(1.0) / 4.0 + 1000.0);
+        assert_eq!(lighting.lighting[0][0], 1.0);
+    }
+
+    #[test]
+    fn flicker_shows_next_to_a_brighter_light() {
+        let steady = Light {
+            cell: 0,
+            color: [1.0, 1.0, 1.0],
+            radius: 15,
+            flicker: Profile::Steady,
+        };
+        let strobe = Light {
+            cell: 2,
+            color: [0.5; 3],
+            radius: 2,
+            flicker: Profile::Strobe,
+        };
+        // The steady light is brighter than the strobe in the middle cell
+        let mut lighting = Lighting::new(&[steady, strobe], &[false; 3], (3, 1));
+        let phase = lighting.lights[1].as_ref().unwrap().flicker.phase;
+        lighting.update((0.25 - phase).rem_euclid(1.0) / 4.0);
+        let on = lighting.lighting[1][0];
+        lighting.update((0.75 - phase).rem_euclid(1.0) / 4.0 + 1.0);
+        let off = lighting.lighting[1][0];
+        assert!(on > off + 0.05);
+        assert!(off > lit(0, 0.7f32.powf(0.8)));
+    }
+}
@@ -0,0 +1,51 @@
+/// Floor texture that is a pool of liquid the player wades through
+#[derive(Copy, Clone)]
+pub struct Liquid {
+    /// How far the surface sits below the floor, in wall heights
+    pub depth: f32,
+    /// Multiplier of the walking speed
+    pub speed: f32,
+    /// How far the surface ripples, in texture units
+    pub waves: f32,
+    /// Light the surface gives off whatever light reaches it, from 0 up
+    pub glow: [f32; 3],
+    /// Health lost per second standing in it
+    pub damage: f32,
+}
+
+impl Liquid {
+    /// Texture position of a surface point moved by the ripples at `time`
+    #[inline(always)]
+    pub fn ripple(&self, position: [f32; 2], time: f32) -> [f32; 2] {
+        // Waves across each axis move the other one, a few of them per texture
+        let phase = std::f32::consts::TAU / 64.0;
+        [
+            position[0] + self.waves * (position[1] * phase + time * 2.0).sin(),
+            position[1] + self.waves * (position[0] * phase + time * 1.5).cos(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn ripples_stay_within_the_waves() {
+        let liquid = Liquid {
+            depth: 0.1,
+            speed: 0.5,
+            waves: 3.0,
+            glow: [0.0; 3],
+            damage: 0.0,
+        };
+        let still = [40.0, 90.0];
+        let moved: Vec<[f32; 2]> = (0..10)
+            .map(|i| liquid.ripple(still, i as f32 * 0.3))
+            .collect();
+        assert!(moved
+            .iter()
+            .all(|p| (p[0] - still[0]).abs() <= 3.0 && (p[1] - still[1]).abs() <= 3.0));
+        assert!(moved.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+}
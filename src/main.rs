@@ -1,8 +1,10 @@
 use ggez::conf::{WindowMode, WindowSetup};
 use ggez::{event, ContextBuilder, GameResult};
 use raycaster::MainState;
+use std::path::Path;
 
 const TITLE: &str = "RayCaster";
+const ASSETS: &str = "assets";
 
 fn main() -> GameResult {
     let window_mode = WindowMode::default().dimensions(1920.0, 1080.0);
@@ -10,8 +12,12 @@ fn main() -> GameResult {
     let (mut ctx, events_loop) = ContextBuilder::new(TITLE, "migue")
         .window_mode(window_mode)
         .window_setup(window_setup)
-        .add_resource_path("assets")
+        .add_resource_path(ASSETS)
         .build()?;
+    // Bakes the lights of the level into the assets instead of playing
+    if std::env::args().any(|arg| arg == "--bake") {
+        return raycaster::bake(&mut ctx, &Path::new(ASSETS).join("level.bake"));
+    }
     let main_state = MainState::new(&mut ctx)?;
     event::run(ctx, events_loop, main_state)
 }
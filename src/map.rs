@@ -1,18 +1,47 @@
 use crate::door::Door;
-use std::{collections::HashMap, path::Path};
+use crate::level::{Legend, Level};
+use crate::liquid::Liquid;
+use crate::portal::Portal;
+use std::{collections::HashMap, path::Path, str::FromStr};
 
 use ggez::{
     graphics::{self, DrawParam, Image, Rect},
-    Context, GameResult,
+    Context, GameError, GameResult,
 };
 
 use crate::player::Player;
 
+/// How mirror walls reflect, `tint` multiplies everything seen in them once per bounce
+#[derive(Copy, Clone)]
+pub struct Mirror {
+    pub tint: [f32; 3],
+    /// Reflections a ray goes through before mirrors are drawn as plain walls
+    pub bounces: usize,
+}
+
+impl Default for Mirror {
+    fn default() -> Self {
+        Self {
+            tint: [0.85, 0.9, 0.95],
+            bounces: 4,
+        }
+    }
+}
+
 pub struct Map {
     pub walls: Vec<Type>,
+    /// Textures of every cell for the ground storey, the upper storey and the frame of a door
+    pub textures: Vec<[usize; 3]>,
     pub floors: Vec<usize>,
+    pub ceilings: Vec<usize>,
+    pub open_sky: Vec<bool>,
     pub solid: Vec<bool>,
     pub doors: HashMap<usize, Door>,
+    pub mirror: Mirror,
+    /// Portals by the map cell they are in
+    pub portals: HashMap<usize, Portal>,
+    /// Liquid of every floor cell that is one
+    pub liquids: Vec<Option<Liquid>>,
     pub minimap: Image,
     pub sb: graphics::spritebatch::SpriteBatch,
 }
@@ -20,23 +49,70 @@ pub struct Map {
 impl Map {
     pub fn new(
         ctx: &mut Context,
-        path_walls: &Path,
-        path_floors: &Path,
+        level: &Level,
         minimap: Image,
         sb: graphics::spritebatch::SpriteBatch,
         map_size: (usize, usize),
     ) -> GameResult<Self> {
         let mut solid = vec![true; map_size.0 * map_size.1];
         let mut doors = HashMap::new();
+        let mut textures = vec![[0; 3]; map_size.0 * map_size.1];
+        let mut open_sky = vec![false; map_size.0 * map_size.1];
+        let walls = read_map_walls(
+            ctx,
+            Path::new(&level.walls),
+            &level.legend,
+            &mut solid,
+            &mut doors,
+            &mut textures,
+        )?;
+        let mut portals = HashMap::new();
+        for portal in level.portals.iter() {
+            let [cell, target] = [portal.cell, portal.target].map(|[x, y]| x + y * map_size.0);
+            if solid[cell] || solid[target] {
+                return Err(GameError::ResourceLoadError(format!(
+                    "portal from {:?} to {:?} has to link open cells",
+                    portal.cell, portal.target
+                )));
+            }
+            portals.insert(cell, *portal);
+        }
+        let floors = read_map_floors(ctx, Path::new(&level.floors), &level.palette)?;
+        let liquids = floors
+            .iter()
+            .map(|floor| level.liquids.get(floor).copied())
+            .collect();
         Ok(Self {
-            walls: read_map_walls(ctx, path_walls, &mut solid, &mut doors)?,
-            floors: read_map_floors(ctx, path_floors)?,
+            walls,
+            textures,
+            floors,
+            ceilings: read_map_ceilings(
+                ctx,
+                Path::new(&level.ceilings),
+                &level.palette,
+                &mut open_sky,
+            )?,
+            open_sky,
             solid,
             doors,
+            mirror: level.mirror,
+            portals,
+            liquids,
             minimap,
             sb,
         })
     }
+
+    /// Atlas texture of a wall, upper storeys can use their own texture. Door frames are
+    /// looked up with the cell of their door
+    #[inline(always)]
+    pub fn wall_texture(&self, wall_type: usize, pos: usize, upper: bool) -> usize {
+        if wall_type == Type::FrameWoodenDoor as usize {
+            self.textures[pos][2]
+        } else {
+            self.textures[pos][upper as usize]
+        }
+    }
     pub fn draw_minimap(
         &mut self,
         ctx: &mut Context,
@@ -143,58 +219,81 @@ impl Map {
 pub fn read_map_walls(
     ctx: &mut Context,
     path: &Path,
+    legend: &[Legend],
     can_pass: &mut Vec<bool>,
     door_offset: &mut HashMap<usize, Door>,
+    textures: &mut [[usize; 3]],
 ) -> GameResult<Vec<Type>> {
     let map = graphics::Image::new(ctx, path)?.to_rgba8(ctx)?;
     let walls: Vec<Type> = map
         .chunks(4)
         .enumerate()
-        .map(|(i, color)| match color {
-            [255, 255, 0, 255] => Type::LightStoneBrick,
-            [0, 0, 0, 255] => Type::Brick,
-            [0, 0, 255, 255] => Type::MossyBrick,
-            [255, 0, 0, 255] => Type::StoneBrick,
-            [0, 255, 0, 255] => Type::MossyStoneBrick,
-            [255, 0, 255, 255] => {
-                let door = Door::new(1.0, false, 0.0, i);
-                door_offset.insert(i, door);
-                Type::WoodenDoor
-            }
-            [255, 64, 0, 255] => {
-                can_pass[i] = false;
-                Type::Cowbeb
-            }
-            [255, 128, 0, 255] => {
-                can_pass[i] = false;
-                Type::MetalBars
-            }
-            [0, 64, 255, 255] => {
-                can_pass[i] = false;
-                Type::Stairs2
-            }
-            [0, 128, 255, 255] => {
-                can_pass[i] = false;
-                Type::Stairs
-            }
-            _ => {
-                can_pass[i] = false;
+        .map(|(i, color)| {
+            let wall_type = match legend.iter().find(|l| l.color == color[..3]) {
+                Some(l) => {
+                    textures[i] = [l.texture, l.upper, l.frame.unwrap_or(l.texture)];
+                    l.wall_type
+                }
+                None => Type::TiledFloor,
+            };
+            match wall_type {
+                Type::WoodenDoor => {
+                    let door = Door::new(1.0, false, 0.0, i);
+                    door_offset.insert(i, door);
+                }
                 Type::TiledFloor
+                | Type::Cowbeb
+                | Type::MetalBars
+                | Type::Stairs
+                | Type::Stairs2 => can_pass[i] = false,
+                _ => (),
             }
+            wall_type
         })
         .collect();
 
     Ok(walls)
 }
 
-pub fn read_map_floors(ctx: &mut Context, path: &Path) -> GameResult<Vec<usize>> {
+/// The red channel of the floor layer indexes the level texture palette
+pub fn read_map_floors(
+    ctx: &mut Context,
+    path: &Path,
+    palette: &HashMap<usize, usize>,
+) -> GameResult<Vec<usize>> {
     let fmap = graphics::Image::new(ctx, path)?.to_rgba8(ctx)?;
-    let floors: Vec<usize> = fmap
-        .into_iter()
+    fmap.into_iter()
         .step_by(4)
-        .map(|r| if r == 0 { 1 } else { 0 })
-        .collect();
-    Ok(floors)
+        .map(|r| palette_texture(palette, r))
+        .collect()
+}
+
+/// The red channel of the ceiling layer indexes the level texture palette,
+/// transparent pixels are cells open to the sky
+pub fn read_map_ceilings(
+    ctx: &mut Context,
+    path: &Path,
+    palette: &HashMap<usize, usize>,
+    open_sky: &mut [bool],
+) -> GameResult<Vec<usize>> {
+    let cmap = graphics::Image::new(ctx, path)?.to_rgba8(ctx)?;
+    cmap.chunks(4)
+        .enumerate()
+        .map(|(i, color)| {
+            open_sky[i] = color[3] == 0;
+            if open_sky[i] {
+                Ok(0)
+            } else {
+                palette_texture(palette, color[0])
+            }
+        })
+        .collect()
+}
+
+fn palette_texture(palette: &HashMap<usize, usize>, index: u8) -> GameResult<usize> {
+    palette.get(&(index as usize)).copied().ok_or_else(|| {
+        GameError::ResourceLoadError(format!("texture index {} is not in the level", index))
+    })
 }
 
 fn get_drawparam(
@@ -230,4 +329,30 @@ pub enum Type {
     MetalBars = 11,
     Stairs = 12,
     Stairs2 = 13,
+    Mirror = 14,
+}
+
+impl FromStr for Type {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "TiledFloor" => Type::TiledFloor,
+            "Moss" => Type::Moss,
+            "Brick" => Type::Brick,
+            "MossyBrick" => Type::MossyBrick,
+            "StoneBrick" => Type::StoneBrick,
+            "MossyStoneBrick" => Type::MossyStoneBrick,
+            "WoodenDoor" => Type::WoodenDoor,
+            "FrameWoodenDoor" => Type::FrameWoodenDoor,
+            "LightStoneBrick" => Type::LightStoneBrick,
+            "TiledCeiling" => Type::TiledCeiling,
+            "Cowbeb" => Type::Cowbeb,
+            "MetalBars" => Type::MetalBars,
+            "Stairs" => Type::Stairs,
+            "Stairs2" => Type::Stairs2,
+            "Mirror" => Type::Mirror,
+            _ => return Err(()),
+        })
+    }
 }
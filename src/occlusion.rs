@@ -0,0 +1,202 @@
+use crate::Orientation;
+
+/// Solid neighbors of a cell, one bit each
+const W: u8 = 1;
+const E: u8 = 1 << 1;
+const N: u8 = 1 << 2;
+const S: u8 = 1 << 3;
+const NW: u8 = 1 << 4;
+const NE: u8 = 1 << 5;
+const SW: u8 = 1 << 6;
+const SE: u8 = 1 << 7;
+
+/// Ambient occlusion from the map geometry, darkening floors and ceilings along the walls,
+/// inside corners and the seams where walls meet the floor or a ceiling. It is kept apart
+/// from the lighting and scales whatever light reaches a surface
+pub struct Occlusion {
+    /// Solid neighbors of every cell
+    neighbors: Vec<u8>,
+    solid: Vec<bool>,
+    open_sky: Vec<bool>,
+    map_size: (usize, usize),
+    /// How dark fully occluded spots get, from 0 to 1
+    pub strength: f32,
+    /// Distance from a wall, in cells, where the darkening fades out
+    pub radius: f32,
+    pub switch: bool,
+}
+
+impl Occlusion {
+    pub fn new(
+        solid: &[bool],
+        open_sky: &[bool],
+        map_size: (usize, usize),
+        strength: f32,
+        radius: f32,
+    ) -> Self {
+        let mut occlusion = Self {
+            neighbors: vec![0; solid.len()],
+            solid: solid.to_vec(),
+            open_sky: open_sky.to_vec(),
+            map_size,
+            strength,
+            radius,
+            switch: true,
+        };
+        (0..solid.len()).for_each(|cell| occlusion.refresh(cell));
+        occlusion
+    }
+
+    /// Changes the solidity of a map cell, like a door opening
+    pub fn set_solid(&mut self, cell: usize, solid: bool) {
+        self.solid[cell] = solid;
+        let (w, h) = self.map_size;
+        let (x, y) = (cell % w, cell / w);
+        for j in y.saturating_sub(1)..(y + 2).min(h) {
+            for i in x.saturating_sub(1)..(x + 2).min(w) {
+                self.refresh(i + j * w);
+            }
+        }
+    }
+
+    fn refresh(&mut self, cell: usize) {
+        let (w, h) = self.map_size;
+        let (x, y) = ((cell % w) as isize, (cell / w) as isize);
+        // Everything outside of the map is solid
+        let solid = |dx: isize, dy: isize| {
+            let (i, j) = (x + dx, y + dy);
+            i < 0
+                || j < 0
+                || i >= w as isize
+                || j >= h as isize
+                || self.solid[i as usize + j as usize * w]
+        };
+        let sides = [
+            (W, -1, 0),
+            (E, 1, 0),
+            (N, 0, -1),
+            (S, 0, 1),
+            (NW, -1, -1),
+            (NE, 1, -1),
+            (SW, -1, 1),
+            (SE, 1, 1),
+        ];
+        self.neighbors[cell] = sides
+            .iter()
+            .filter(|&&(_, dx, dy)| solid(dx, dy))
+            .fold(0, |mask, &(bit, _, _)| mask | bit);
+    }
+
+    /// Darkening of a spot `distance` away from an occluding wall
+    #[inline(always)]
+    fn near(&self, distance: f32) -> f32 {
+        if distance < self.radius {
+            let t = 1.0 - distance / self.radius;
+            t * t
+        } else {
+            0.0
+        }
+    }
+
+    #[inline(always)]
+    fn factor(&self, occluded: f32) -> f32 {
+        1.0 - self.strength * occluded.min(1.0)
+    }
+
+    /// Light reaching a point of a floor or ceiling, `x` and `y` go from 0 to 1 across the
+    /// cell. Any storey can be given, both have the same walls
+    #[inline(always)]
+    pub fn floor(&self, x: f32, y: f32, cell: usize) -> f32 {
+        if !self.switch {
+            return 1.0;
+        }
+        let cell = cell % (self.map_size.0 * self.map_size.1);
+        let mask = self.neighbors[cell];
+        let has = |bits: u8| mask & bits == bits;
+        let mut occluded = 0.0;
+        for (bit, distance) in [(W, x), (E, 1.0 - x), (N, y), (S, 1.0 - y)] {
+            if has(bit) {
+                occluded += self.near(distance);
+            }
+        }
+        // Outer corners of a wall only darken what the sides next to them don't
+        for (bit, sides, dx, dy) in [
+            (NW, W | N, x, y),
+            (NE, E | N, 1.0 - x, y),
+            (SW, W | S, x, 1.0 - y),
+            (SE, E | S, 1.0 - x, 1.0 - y),
+        ] {
+            if has(bit) && mask & sides == 0 {
+                occluded += self.near((dx * dx + dy * dy).sqrt());
+            }
+        }
+        self.factor(occluded)
+    }
+
+    /// Light reaching a point of a wall face. `x` goes from 0 to 1 along the texture and
+    /// `height` from 0 at the bottom of the storey to 1 at its top. Walls darken where they
+    /// meet the floor, a ceiling over the upper storey and other walls at inside corners
+    #[inline(always)]
+    pub fn wall(
+        &self,
+        x: f32,
+        height: f32,
+        pos: usize,
+        orientation: &Orientation,
+        up: bool,
+    ) -> f32 {
+        if !self.switch {
+            return 1.0;
+        }
+        let w = self.map_size.0;
+        let pos = pos % (w * self.map_size.1);
+        // Cell in front of the face and the sides of it the texture's x starts and ends at
+        let (front, start, end) = match orientation {
+            Orientation::N => (pos - w, E, W),
+            Orientation::S => (pos + w, W, E),
+            Orientation::E => (pos - 1, N, S),
+            Orientation::W => (pos + 1, S, N),
+        };
+        let mask = self.neighbors[front];
+        let mut occluded = 0.0;
+        if mask & start != 0 {
+            occluded += self.near(x);
+        }
+        if mask & end != 0 {
+            occluded += self.near(1.0 - x);
+        }
+        if !up {
+            occluded += self.near(height);
+        } else if !self.open_sky[front] {
+            occluded += self.near(1.0 - height);
+        }
+        self.factor(occluded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn walls_and_corners_darken() {
+        // 3x3 room surrounded by the edge of the map with a pillar in the middle
+        let mut solid = [false; 9];
+        solid[4] = true;
+        let mut occlusion = Occlusion::new(&solid, &[true; 9], (3, 3), 0.5, 0.5);
+        // Middle of the top left cell is far from everything
+        assert_eq!(occlusion.floor(0.5, 0.5, 0), 1.0);
+        assert_eq!(occlusion.floor(0.0, 0.5, 0), 0.5);
+        assert!(occlusion.floor(0.9, 0.9, 0) < 1.0);
+        assert_eq!(occlusion.floor(0.9, 0.5, 0), 1.0);
+        // The pillar's north face darkens along the floor, its top is under the open sky
+        assert_eq!(occlusion.wall(0.5, 0.5, 4, &Orientation::N, false), 1.0);
+        assert_eq!(occlusion.wall(0.5, 0.0, 4, &Orientation::N, false), 0.5);
+        assert_eq!(occlusion.wall(0.5, 1.0, 4, &Orientation::N, true), 1.0);
+
+        occlusion.set_solid(4, false);
+        assert_eq!(occlusion.floor(0.9, 0.9, 0), 1.0);
+        occlusion.switch = false;
+        assert_eq!(occlusion.floor(0.0, 0.5, 0), 1.0);
+    }
+}
@@ -0,0 +1,454 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use ggez::{Context, GameResult};
+use rand::Rng;
+
+use crate::{
+    fog::Atmosphere,
+    lighting::Lighting,
+    player::Player,
+    portal::Portal,
+    render::{Column, Views},
+    texture::UNITS,
+    utilities::{datafile, vector2::Vector2},
+};
+
+/// Most particles alive at once, emitters wait while there are this many
+const MAX_PARTICLES: usize = 4000;
+
+/// How a particle mixes with what's behind it
+#[derive(Copy, Clone, PartialEq)]
+pub enum Blend {
+    /// Adds its light, for glowing embers and sparks
+    Additive,
+    /// Covers what's behind it by its alpha and is lit like the floor under it
+    Alpha,
+}
+
+impl FromStr for Blend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "additive" => Blend::Additive,
+            "alpha" => Blend::Alpha,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// What a kind of particle looks like and how it moves, sizes are in world units
+pub struct Style {
+    pub name: String,
+    /// Seconds a particle lives
+    life: f32,
+    size: f32,
+    /// Speed particles are thrown out at, in cells per second
+    speed: f32,
+    /// Downwards acceleration in walls per second squared, negative rises
+    gravity: f32,
+    blend: Blend,
+    /// Colors over the life of a particle, rgb from 0 to 255 and alpha from 0 to 1, at times
+    /// from 0 to 1
+    colors: Vec<(f32, [f32; 4])>,
+}
+
+impl Style {
+    /// Color at `t` of the way through a particle's life
+    fn color(&self, t: f32) -> [f32; 4] {
+        let next = self.colors.iter().position(|&(time, _)| time > t);
+        match next {
+            Some(0) => self.colors[0].1,
+            Some(i) => {
+                let ((t0, c0), (t1, c1)) = (self.colors[i - 1], self.colors[i]);
+                let f = (t - t0) / (t1 - t0);
+                [0, 1, 2, 3].map(|k| c0[k] + (c1[k] - c0[k]) * f)
+            }
+            None => self.colors.last().map_or([0.0; 4], |&(_, color)| color),
+        }
+    }
+}
+
+/// `[styles]` lines are `name life size speed gravity blend`, `[colors]` lines are
+/// `style time r g b a` in the order of their times from 0 to 1
+pub fn load_styles(ctx: &mut Context, path: &Path) -> GameResult<Vec<Style>> {
+    let mut styles: Vec<Style> = vec![];
+    for entry in datafile::read(ctx, path)? {
+        match entry.section.as_str() {
+            "styles" => {
+                let blend = entry.name(5)?;
+                styles.push(Style {
+                    name: entry.name(0)?.to_string(),
+                    life: entry.field(1)?,
+                    size: entry.field(2)?,
+                    speed: entry.field(3)?,
+                    gravity: entry.field(4)?,
+                    blend: blend
+                        .parse()
+                        .map_err(|_| entry.error(&format!("unknown blend '{}'", blend)))?,
+                    colors: vec![],
+                });
+            }
+            "colors" => {
+                let name = entry.name(0)?;
+                let style = styles
+                    .iter_mut()
+                    .find(|style| style.name == name)
+                    .ok_or_else(|| entry.error(&format!("unknown style '{}'", name)))?;
+                let time: f32 = entry.field(1)?;
+                if style.colors.last().is_some_and(|&(last, _)| time <= last) {
+                    return Err(entry.error("colors have to come in the order of their times"));
+                }
+                style.colors.push((
+                    time,
+                    [
+                        entry.field(2)?,
+                        entry.field(3)?,
+                        entry.field(4)?,
+                        entry.field(5)?,
+                    ],
+                ));
+            }
+            section => return Err(entry.error(&format!("unknown section '{}'", section))),
+        }
+    }
+    Ok(styles)
+}
+
+/// Keeps throwing out particles of a style around a point, heights are in walls from the
+/// ground floor
+#[derive(Copy, Clone)]
+pub struct Emitter {
+    pub style: usize,
+    pub pos: [f32; 3],
+    /// Radius of the disc particles start in, in cells
+    pub spread: f32,
+    /// Particles per second
+    pub rate: f32,
+    /// Share of a particle left over from the last update
+    pub carry: f32,
+}
+
+struct Particle {
+    style: usize,
+    pos: [f32; 3],
+    velocity: [f32; 3],
+    age: f32,
+    /// Floor of the storey it started on
+    ground: f32,
+}
+
+/// A particle projected on screen, columns and rows of its middle and radii
+struct Splat {
+    /// View of the mirrors and portals it is seen in, `None` straight ahead
+    view: Option<usize>,
+    center: [f32; 2],
+    radius: [f32; 2],
+    depth: f32,
+    color: [f32; 4],
+    blend: Blend,
+}
+
+pub struct Particles {
+    styles: Vec<Style>,
+    emitters: Vec<Emitter>,
+    particles: Vec<Particle>,
+    /// Particles on screen from back to front
+    splats: Vec<Splat>,
+    /// Walls and closed doors, particles that fly into them are gone
+    solid: Vec<bool>,
+    map_size: (usize, usize),
+}
+
+impl Particles {
+    pub fn new(
+        styles: Vec<Style>,
+        emitters: Vec<Emitter>,
+        solid: &[bool],
+        map_size: (usize, usize),
+    ) -> Self {
+        Self {
+            styles,
+            emitters,
+            particles: vec![],
+            splats: vec![],
+            solid: solid.to_vec(),
+            map_size,
+        }
+    }
+
+    /// Updates a cell when a door opens or closes
+    pub fn set_solid(&mut self, cell: usize, solid: bool) {
+        self.solid[cell] = solid;
+    }
+
+    pub fn style(&self, name: &str) -> GameResult<usize> {
+        find(&self.styles, name).ok_or_else(|| {
+            ggez::GameError::ResourceLoadError(format!("unknown particle style '{}'", name))
+        })
+    }
+
+    /// Throws out `count` particles at once, like dust from a door or sparks from a hit
+    pub fn burst(&mut self, style: usize, pos: [f32; 3], spread: f32, count: usize) {
+        for _ in 0..count.min(MAX_PARTICLES.saturating_sub(self.particles.len())) {
+            self.spawn(style, pos, spread);
+        }
+    }
+
+    fn spawn(&mut self, style: usize, pos: [f32; 3], spread: f32) {
+        let mut rng = rand::thread_rng();
+        let offset = random_in_sphere(&mut rng);
+        let direction = random_in_sphere(&mut rng);
+        let speed = self.styles[style].speed;
+        self.particles.push(Particle {
+            style,
+            pos: [
+                pos[0] + offset[0] * spread,
+                pos[1] + offset[1] * spread,
+                pos[2],
+            ],
+            velocity: direction.map(|d| d * speed),
+            age: 0.0,
+            ground: pos[2].floor(),
+        });
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        for i in 0..self.emitters.len() {
+            let emitter = &mut self.emitters[i];
+            emitter.carry += emitter.rate * dt;
+            let count = emitter.carry as usize;
+            emitter.carry -= count as f32;
+            let emitter = *emitter;
+            self.burst(emitter.style, emitter.pos, emitter.spread, count);
+        }
+        let (w, h) = (self.map_size.0 as f32, self.map_size.1 as f32);
+        let (styles, solid) = (&self.styles, &self.solid);
+        self.particles.retain_mut(|particle| {
+            let style = &styles[particle.style];
+            particle.age += dt;
+            particle.velocity[2] -= style.gravity * dt;
+            for (p, v) in particle.pos.iter_mut().zip(particle.velocity) {
+                *p += v * dt;
+            }
+            // Bounce off the floor losing most of the speed
+            if particle.pos[2] < particle.ground {
+                particle.pos[2] = particle.ground;
+                particle.velocity = [
+                    particle.velocity[0] * 0.6,
+                    particle.velocity[1] * 0.6,
+                    -particle.velocity[2] * 0.3,
+                ];
+            }
+            particle.age < style.life
+                && (0.0..w).contains(&particle.pos[0])
+                && (0.0..h).contains(&particle.pos[1])
+                && !solid[particle.pos[0] as usize + particle.pos[1] as usize * w as usize]
+        });
+    }
+
+    /// Projects the particles on screen like sprites, straight ahead and where they show up in
+    /// each of the `views` the rays went into, lighting them and fogging them by the cell
+    /// they are in
+    #[allow(clippy::too_many_arguments)]
+    pub fn project(
+        &mut self,
+        player: &Player,
+        lighting: &Lighting,
+        atmosphere: &Atmosphere,
+        (w, h): (f32, f32),
+        rays_per_pixel: f32,
+        views: &Views,
+        portals: &HashMap<usize, Portal>,
+    ) {
+        let inv_det =
+            1.0 / (player.plane.x * player.dir_norm.y - player.dir_norm.x * player.plane.y);
+        let columns = w / rays_per_pixel;
+        let cells = self.map_size.0 * self.map_size.1;
+        self.splats.clear();
+        for particle in self.particles.iter() {
+            let style = &self.styles[particle.style];
+            let mut lit = style.color(particle.age / style.life);
+            let layer = (particle.ground as usize).min(1) * cells;
+            let cell =
+                particle.pos[0] as usize + particle.pos[1] as usize * self.map_size.0 + layer;
+            if style.blend == Blend::Alpha {
+                let light = lighting.get_lighting_floor(
+                    particle.pos[0].fract() * UNITS as f32,
+                    particle.pos[1].fract() * UNITS as f32,
+                    cell,
+                );
+                (0..3).for_each(|k| lit[k] *= light[k]);
+            }
+            let pos = Vector2::new(particle.pos[0], particle.pos[1]);
+            let seen = (0..views.len()).map(|i| (Some(i), views.seen(i, portals, pos, 0.0).0));
+            for (view, pos) in std::iter::once((None, pos)).chain(seen) {
+                let dx = pos.x - player.pos.x;
+                let dy = pos.y - player.pos.y;
+                let transform_x = inv_det * (player.dir_norm.y * dx - player.dir_norm.x * dy);
+                let depth = inv_det * (-player.plane.y * dx + player.plane.x * dy);
+                if depth <= 0.05 {
+                    continue;
+                }
+                let scale = player.planedist / depth;
+                let radius = style.size * scale * 0.5;
+                let center = [
+                    columns * 0.5 * (1.0 + transform_x / depth),
+                    h * 0.5
+                        + player.pitch
+                        + (player.jump + (0.5 - particle.pos[2]) * player.planedist) / depth,
+                ];
+                let radius = [(radius / rays_per_pixel).max(0.75), radius.max(1.0)];
+                if center[0] + radius[0] < 0.0
+                    || center[0] - radius[0] >= columns
+                    || center[1] + radius[1] < 0.0
+                    || center[1] - radius[1] >= h
+                {
+                    continue;
+                }
+                let mut color = lit;
+                if let Some(fog) = atmosphere.at(cell) {
+                    let amount = fog.amount(depth);
+                    match style.blend {
+                        // Glowing particles fade out rather than taking the fog's color
+                        Blend::Additive => (0..3).for_each(|k| color[k] *= 1.0 - amount),
+                        Blend::Alpha => {
+                            (0..3).for_each(|k| color[k] += (fog.color[k] - color[k]) * amount)
+                        }
+                    }
+                }
+                self.splats.push(Splat {
+                    view,
+                    center,
+                    radius,
+                    depth,
+                    color,
+                    blend: style.blend,
+                });
+            }
+        }
+        self.splats.sort_by(|a, b| {
+            b.depth
+                .partial_cmp(&a.depth)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+
+    /// Blends the particles over column `j`, they are soft discs that don't hide each other
+    pub fn draw(&self, column: &mut Column, j: usize) {
+        let stripe = j as f32 + 0.5;
+        for splat in self.splats.iter() {
+            let dx = (stripe - splat.center[0]) / splat.radius[0];
+            if dx.abs() >= 1.0 {
+                continue;
+            }
+            let start = (splat.center[1] - splat.radius[1]).max(0.0) as usize;
+            let end = ((splat.center[1] + splat.radius[1]).max(0.0) as usize)
+                .min(column.depth.len().saturating_sub(1));
+            for y in start..=end {
+                let dy = (y as f32 + 0.5 - splat.center[1]) / splat.radius[1];
+                let falloff = 1.0 - dx * dx - dy * dy;
+                if falloff <= 0.0
+                    || !column.visible(y, splat.depth)
+                    || !column.shows(splat.view, splat.depth)
+                {
+                    continue;
+                }
+                let weight = falloff * splat.color[3];
+                for (k, p) in column.pixels[y * 4..y * 4 + 3].iter_mut().enumerate() {
+                    let c = *p as f32;
+                    *p = match splat.blend {
+                        Blend::Additive => (c + splat.color[k] * weight).min(255.0),
+                        Blend::Alpha => c + (splat.color[k] - c) * weight,
+                    } as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Index of the style called `name`
+pub fn find(styles: &[Style], name: &str) -> Option<usize> {
+    styles.iter().position(|style| style.name == name)
+}
+
+/// Random point inside the unit sphere
+fn random_in_sphere(rng: &mut impl Rng) -> [f32; 3] {
+    loop {
+        let p = [
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        ];
+        if p[0] * p[0] + p[1] * p[1] + p[2] * p[2] <= 1.0 {
+            return p;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn particles_fall_fade_and_die() {
+        let style = Style {
+            name: "sparks".to_string(),
+            life: 1.0,
+            size: 0.05,
+            speed: 0.0,
+            gravity: 10.0,
+            blend: Blend::Additive,
+            colors: vec![
+                (0.0, [255.0, 200.0, 0.0, 1.0]),
+                (1.0, [255.0, 0.0, 0.0, 0.0]),
+            ],
+        };
+        assert_eq!(style.color(0.5), [255.0, 100.0, 0.0, 0.5]);
+        assert_eq!(style.color(2.0), [255.0, 0.0, 0.0, 0.0]);
+
+        let mut particles = Particles::new(vec![style], vec![], &[false; 16], (4, 4));
+        particles.burst(0, [2.0, 2.0, 0.5], 0.0, 3);
+        particles.update(0.2);
+        assert_eq!(particles.particles.len(), 3);
+        // Sparks thrown out with no speed drop straight down and stop on the floor
+        assert!(particles.particles.iter().all(|p| p.pos[2] < 0.5));
+        (0..3).for_each(|_| particles.update(0.2));
+        assert!(particles.particles.iter().all(|p| p.pos[2] >= 0.0));
+        particles.update(0.3);
+        assert!(particles.particles.is_empty());
+    }
+
+    #[test]
+    fn particles_stop_at_walls_and_doors() {
+        let style = Style {
+            name: "dust".to_string(),
+            life: 10.0,
+            size: 0.05,
+            speed: 0.0,
+            gravity: 0.0,
+            blend: Blend::Alpha,
+            colors: vec![(0.0, [255.0; 4])],
+        };
+        // A corridor with a door at its third cell
+        let mut solid = [true; 12];
+        solid[4..8].fill(false);
+        solid[6] = true;
+        let mut particles = Particles::new(vec![style], vec![], &solid, (4, 3));
+        let throw = |particles: &mut Particles| {
+            particles.burst(0, [0.5, 1.5, 0.5], 0.0, 1);
+            particles.particles[0].velocity = [1.0, 0.0, 0.0];
+        };
+        throw(&mut particles);
+        particles.update(1.0);
+        assert_eq!(particles.particles.len(), 1);
+        particles.update(1.0);
+        assert!(particles.particles.is_empty());
+
+        // Dust goes through once the door is open
+        particles.set_solid(6, false);
+        throw(&mut particles);
+        (0..3).for_each(|_| particles.update(1.0));
+        assert_eq!(particles.particles[0].pos[0], 3.5);
+    }
+}
@@ -3,7 +3,7 @@ use ggez::{
     Context, GameResult,
 };
 
-use crate::{map::Type, utilities::vector2::Vector2};
+use crate::{flashlight::Flashlight, liquid::Liquid, map::Type, utilities::vector2::Vector2};
 
 pub struct Player {
     pub pos: Vector2<f32>,
@@ -15,7 +15,13 @@ pub struct Player {
     pub walking: bool,
     pub height: f32,
     pub current_wall: Type,
+    /// Liquid the player is wading through
+    pub liquid: Option<Liquid>,
+    /// Drops in liquids that hurt, the player starts over at `spawn` once it runs out
+    pub health: f32,
+    spawn: Vector2<f32>,
     pub mesh: Mesh,
+    pub flashlight: Flashlight,
 }
 
 impl Player {
@@ -48,18 +54,42 @@ impl Player {
             jump,
             height: 150.0,
             current_wall: Type::TiledFloor,
+            liquid: None,
+            health: 100.0,
+            spawn: pos,
             walking: false,
+            flashlight: Flashlight::default(),
         })
     }
 
+    /// Puts the player back where they started with full health
+    pub fn respawn(&mut self) {
+        self.pos = self.spawn;
+        self.health = 100.0;
+        self.liquid = None;
+    }
+
     pub fn draw_circle(&self, ctx: &mut Context) -> GameResult<()> {
         graphics::draw(ctx, &self.mesh, DrawParam::default())?;
         Ok(())
     }
 
-    pub fn update(&mut self, wall: Type, buffer_walking: &[f32], time: f32) {
+    pub fn update(
+        &mut self,
+        wall: Type,
+        liquid: Option<Liquid>,
+        buffer_walking: &[f32],
+        time: f32,
+        dt: f32,
+    ) {
         self.current_wall = wall;
+        self.liquid = liquid;
         self.jump = self.height;
+        if let Some(liquid) = liquid {
+            // Standing on the bottom of the pool
+            self.jump -= liquid.depth * self.planedist;
+            self.health = (self.health - liquid.damage * dt).max(0.0);
+        }
         if self.walking {
             let delta_jump = buffer_walking[(time % 0.5 * 300.0) as usize];
             self.jump += delta_jump * 35.0;
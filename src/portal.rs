@@ -0,0 +1,72 @@
+use crate::utilities::vector2::Vector2;
+
+/// Map cell that rays and the player entering it come out of `target` instead, turned
+/// counterclockwise by `turns` quarter turns
+#[derive(Copy, Clone)]
+pub struct Portal {
+    pub cell: [usize; 2],
+    pub target: [usize; 2],
+    pub turns: usize,
+}
+
+impl Portal {
+    /// Angle the view turns by going through, in radians
+    pub fn angle(&self) -> f32 {
+        self.turns as f32 * std::f32::consts::FRAC_PI_2
+    }
+
+    /// A direction after going through
+    #[inline(always)]
+    pub fn direction(&self, v: Vector2<f32>) -> Vector2<f32> {
+        turn(v, self.turns)
+    }
+
+    /// A point in or next to the portal cell moved to the same spot around the target
+    #[inline(always)]
+    pub fn position(&self, p: Vector2<f32>) -> Vector2<f32> {
+        self.direction(p - center(self.cell)) + center(self.target)
+    }
+
+    /// Where something around the target shows up when seen through the portal
+    pub fn seen_from(&self, p: Vector2<f32>) -> Vector2<f32> {
+        turn(p - center(self.target), 4 - self.turns % 4) + center(self.cell)
+    }
+}
+
+fn center(cell: [usize; 2]) -> Vector2<f32> {
+    Vector2::new(cell[0] as f32 + 0.5, cell[1] as f32 + 0.5)
+}
+
+/// Exact quarter turns, the same way as `Vector2::rotate`
+fn turn(v: Vector2<f32>, turns: usize) -> Vector2<f32> {
+    match turns % 4 {
+        0 => v,
+        1 => Vector2::new(-v.y, v.x),
+        2 => Vector2::new(-v.x, -v.y),
+        _ => Vector2::new(v.y, -v.x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn going_through_and_seeing_through_match() {
+        let portal = Portal {
+            cell: [2, 3],
+            target: [10, 5],
+            turns: 1,
+        };
+        let dir = Vector2::new(1.0, 0.0);
+        let turned = portal.direction(dir);
+        let rotated = Vector2::rotate(dir, portal.angle());
+        assert!((turned.x - rotated.x).abs() < 1e-6 && (turned.y - rotated.y).abs() < 1e-6);
+
+        // Entering the west side of the portal cell comes out of the north side of the target
+        let through = portal.position(Vector2::new(2.0, 3.5));
+        assert_eq!((through.x, through.y), (10.5, 5.0));
+        let back = portal.seen_from(through);
+        assert_eq!((back.x, back.y), (2.0, 3.5));
+    }
+}
@@ -0,0 +1,263 @@
+use std::path::Path;
+
+use ggez::{Context, GameResult};
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::utilities::datafile;
+
+/// 4x4 ordered dithering thresholds
+const BAYER: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+/// One step of the post processing chain
+enum Effect {
+    /// Lookup table of the gamma and brightness correction of each channel value
+    Tone(Box<[u8; 256]>),
+    /// Quantization to 8 levels of red and green and 4 of blue, 256 colors in all
+    Palette { dither: bool },
+    /// Darkens every other row
+    Scanlines(f32),
+    /// Darkens towards the corners
+    Vignette(f32),
+    /// Tints the whole screen with `color` after taking damage
+    Flash { color: [f32; 3], strength: f32 },
+}
+
+/// CPU effects applied to the frame before it is uploaded. Bending the screen like a CRT
+/// and shaking it move pixels around, so they are done first, then the other effects in the
+/// order they are listed
+pub struct Post {
+    effects: Vec<Effect>,
+    /// How much the screen bulges in the middle, 0 for a flat screen
+    curvature: f32,
+    /// Largest offset of the shake in pixels
+    shake: f32,
+    /// Seconds the damage flash and shake last
+    duration: f32,
+    /// Damage effects left, from 1 right after taking damage to 0
+    hurt: f32,
+    offset: [f32; 2],
+    /// Copy of the frame for the effects that move pixels around
+    scratch: Vec<u8>,
+    pub switch: bool,
+}
+
+impl Post {
+    /// Each line of `[effects]` is an effect followed by its settings, `gamma n [brightness=n]`,
+    /// `palette [dither=bool]`, `scanlines strength`, `curvature amount`, `vignette strength`,
+    /// `flash r g b strength` or `shake pixels`. `[damage]` holds how many seconds the
+    /// flash and shake last
+    pub fn load(ctx: &mut Context, path: &Path) -> GameResult<Self> {
+        let mut post = Self {
+            effects: vec![],
+            curvature: 0.0,
+            shake: 0.0,
+            duration: 0.4,
+            hurt: 0.0,
+            offset: [0.0; 2],
+            scratch: vec![],
+            switch: true,
+        };
+        for entry in datafile::read(ctx, path)? {
+            match (entry.section.as_str(), entry.name(0)?) {
+                ("effects", "gamma") => {
+                    let gamma: f32 = entry.field(1)?;
+                    let brightness = entry.option("brightness")?.unwrap_or(1.0);
+                    if gamma <= 0.0 {
+                        return Err(entry.error("gamma has to be positive"));
+                    }
+                    post.effects
+                        .push(Effect::Tone(Box::new(tone(gamma, brightness))));
+                }
+                ("effects", "palette") => post.effects.push(Effect::Palette {
+                    dither: entry.option("dither")?.unwrap_or(true),
+                }),
+                ("effects", "scanlines") => post.effects.push(Effect::Scanlines(entry.field(1)?)),
+                ("effects", "vignette") => post.effects.push(Effect::Vignette(entry.field(1)?)),
+                ("effects", "flash") => post.effects.push(Effect::Flash {
+                    color: [entry.field(1)?, entry.field(2)?, entry.field(3)?],
+                    strength: entry.field(4)?,
+                }),
+                ("effects", "curvature") => post.curvature = entry.field(1)?,
+                ("effects", "shake") => post.shake = entry.field(1)?,
+                ("damage", _) => post.duration = entry.field(0)?,
+                (section, name) => {
+                    return Err(entry.error(&format!("unknown effect '{}' in [{}]", name, section)))
+                }
+            }
+        }
+        Ok(post)
+    }
+
+    /// Starts the damage flash and shake over
+    pub fn hurt(&mut self) {
+        self.hurt = 1.0;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.hurt = (self.hurt - dt / self.duration).max(0.0);
+        let amplitude = self.shake * self.hurt;
+        let mut rng = rand::thread_rng();
+        self.offset = if amplitude > 0.0 {
+            [
+                rng.gen_range(-amplitude..=amplitude),
+                rng.gen_range(-amplitude..=amplitude),
+            ]
+        } else {
+            [0.0; 2]
+        };
+    }
+
+    /// Runs the chain over a frame of `w` by `h` pixels, stored a screen column at a time
+    /// from the right
+    pub fn apply(&mut self, frame: &mut [u8], (w, h): (usize, usize)) {
+        if !self.switch {
+            return;
+        }
+        if self.curvature != 0.0 || self.offset != [0.0; 2] {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(frame);
+            let (source, curvature, offset) = (&self.scratch, self.curvature, self.offset);
+            frame
+                .par_chunks_mut(h * 4)
+                .enumerate()
+                .for_each(|(r, column)| {
+                    let x = w - 1 - r;
+                    for (y, pixel) in column.chunks_mut(4).enumerate() {
+                        let [u, v] = centered(x, y, w, h);
+                        let bend = 1.0 + curvature * (u * u + v * v);
+                        let sx = ((u * bend + 1.0) * 0.5 * w as f32 + offset[0]).floor();
+                        let sy = ((v * bend + 1.0) * 0.5 * h as f32 + offset[1]).floor();
+                        if sx < 0.0 || sy < 0.0 || sx >= w as f32 || sy >= h as f32 {
+                            pixel[..3].fill(0);
+                        } else {
+                            let i = ((w - 1 - sx as usize) * h + sy as usize) * 4;
+                            pixel[..3].copy_from_slice(&source[i..i + 3]);
+                        }
+                    }
+                });
+        }
+        if self.effects.is_empty() {
+            return;
+        }
+        let (effects, hurt) = (&self.effects, self.hurt);
+        frame
+            .par_chunks_mut(h * 4)
+            .enumerate()
+            .for_each(|(r, column)| {
+                let x = w - 1 - r;
+                for (y, pixel) in column.chunks_mut(4).enumerate() {
+                    let mut color = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+                    for effect in effects.iter() {
+                        filter(effect, &mut color, [x, y], (w, h), hurt);
+                    }
+                    for (p, c) in pixel.iter_mut().zip(color) {
+                        *p = c.clamp(0.0, 255.0) as u8;
+                    }
+                }
+            });
+    }
+}
+
+/// Screen position from -1 to 1 across, the middle of the screen is 0
+#[inline(always)]
+fn centered(x: usize, y: usize, w: usize, h: usize) -> [f32; 2] {
+    [
+        (x as f32 + 0.5) / w as f32 * 2.0 - 1.0,
+        (y as f32 + 0.5) / h as f32 * 2.0 - 1.0,
+    ]
+}
+
+/// Runs one effect on the color of the pixel at `x`, `y`
+#[inline(always)]
+fn filter(
+    effect: &Effect,
+    color: &mut [f32; 3],
+    [x, y]: [usize; 2],
+    (w, h): (usize, usize),
+    hurt: f32,
+) {
+    match effect {
+        Effect::Tone(table) => {
+            for c in color.iter_mut() {
+                *c = table[c.clamp(0.0, 255.0) as usize] as f32;
+            }
+        }
+        Effect::Palette { dither } => {
+            let threshold = if *dither {
+                (BAYER[y % 4][x % 4] + 0.5) / 16.0 - 0.5
+            } else {
+                0.0
+            };
+            for (c, levels) in color.iter_mut().zip([8.0, 8.0, 4.0]) {
+                let step = 255.0 / (levels - 1.0);
+                *c = ((*c / step + threshold).round().clamp(0.0, levels - 1.0)) * step;
+            }
+        }
+        &Effect::Scanlines(strength) => {
+            if y % 2 == 1 {
+                color.iter_mut().for_each(|c| *c *= 1.0 - strength);
+            }
+        }
+        &Effect::Vignette(strength) => {
+            let [u, v] = centered(x, y, w, h);
+            let shade = 1.0 - strength * (u * u + v * v) * 0.5;
+            color.iter_mut().for_each(|c| *c *= shade);
+        }
+        &Effect::Flash {
+            color: tint,
+            strength,
+        } => {
+            let amount = strength * hurt;
+            for (c, t) in color.iter_mut().zip(tint) {
+                *c += (t - *c) * amount;
+            }
+        }
+    }
+}
+
+/// Gamma and brightness corrected value of every channel value
+fn tone(gamma: f32, brightness: f32) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, t) in table.iter_mut().enumerate() {
+        *t = ((i as f32 / 255.0).powf(1.0 / gamma) * brightness * 255.0).clamp(0.0, 255.0) as u8;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn palette_and_tone() {
+        let table = tone(2.0, 1.0);
+        assert_eq!((table[0], table[255]), (0, 255));
+        assert!(table[64] > 64);
+
+        // Dithering spreads a color between the two closest levels of the palette
+        let step = 255.0 / 7.0;
+        let mut reds = vec![];
+        for y in 0..4 {
+            for x in 0..4 {
+                let mut color = [100.0; 3];
+                filter(
+                    &Effect::Palette { dither: true },
+                    &mut color,
+                    [x, y],
+                    (4, 4),
+                    0.0,
+                );
+                reds.push(color[0]);
+            }
+        }
+        assert!(reds.iter().all(|&r| r == step * 2.0 || r == step * 3.0));
+        let mean = reds.iter().sum::<f32>() / 16.0;
+        assert!((mean - 100.0).abs() < step / 8.0);
+    }
+}
@@ -1,10 +1,89 @@
+use std::collections::HashMap;
+
 use crate::{
+    decal::Surface,
     map::Type,
-    sprite::Sprite,
-    utilities::{math::ffmin, vector2::Vector2},
-    MainState, Orientation,
+    portal::Portal,
+    screen::Relief,
+    texture::{Sample, UNITS},
+    utilities::vector2::Vector2,
+    MainState, Orientation, FOV,
 };
 const RAYSPERPIXEL: usize = 2;
+/// Rays give up after travelling this far without hitting a wall
+const MAX_DISTANCE: f32 = 100.0;
+/// Portals a ray goes through before giving up, so facing portals don't loop forever
+const MAX_PORTALS: usize = 8;
+
+/// Walk through the map grid along a ray, one cell boundary at a time
+pub struct Dda {
+    pub map_checkv: Vector2<f32>,
+    pub ray_length1_d: Vector2<f32>,
+    pub ray_unitstep_size: Vector2<f32>,
+    pub stepv: Vector2<f32>,
+    dir: Vector2<f32>,
+}
+
+impl Dda {
+    pub fn new(startv: Vector2<f32>, ray_dir_norm: Vector2<f32>) -> Self {
+        let ray_unitstep_size = Vector2::new(
+            (1.0 + (ray_dir_norm.y / ray_dir_norm.x) * (ray_dir_norm.y / ray_dir_norm.x)).sqrt(),
+            (1.0 + (ray_dir_norm.x / ray_dir_norm.y) * (ray_dir_norm.x / ray_dir_norm.y)).sqrt(),
+        );
+        let map_checkv = Vector2::new(startv.x.floor(), startv.y.floor());
+        let mut ray_length1_d = Vector2::new(0.0, 0.0);
+        let mut stepv = Vector2::new(0.0, 0.0);
+
+        if ray_dir_norm.x < 0.0 {
+            stepv.x = -1.0;
+            ray_length1_d.x = (startv.x - map_checkv.x) * ray_unitstep_size.x;
+        } else {
+            stepv.x = 1.0;
+            ray_length1_d.x = (map_checkv.x + 1.0 - startv.x) * ray_unitstep_size.x;
+        }
+
+        if ray_dir_norm.y < 0.0 {
+            stepv.y = -1.0;
+            ray_length1_d.y = (startv.y - map_checkv.y) * ray_unitstep_size.y;
+        } else {
+            stepv.y = 1.0;
+            ray_length1_d.y = (map_checkv.y + 1.0 - startv.y) * ray_unitstep_size.y;
+        }
+        Self {
+            map_checkv,
+            ray_length1_d,
+            ray_unitstep_size,
+            stepv,
+            dir: ray_dir_norm,
+        }
+    }
+
+    /// Moves into the next cell, returns the distance to it and the side it was entered from
+    #[inline(always)]
+    pub fn step(&mut self) -> (f32, Orientation) {
+        if self.ray_length1_d.x < self.ray_length1_d.y {
+            self.map_checkv.x += self.stepv.x;
+            let distance = self.ray_length1_d.x;
+            self.ray_length1_d.x += self.ray_unitstep_size.x;
+
+            if self.dir.x < 0.0 {
+                (distance, Orientation::W)
+            } else {
+                (distance, Orientation::E)
+            }
+        } else {
+            self.map_checkv.y += self.stepv.y;
+            let distance = self.ray_length1_d.y;
+            self.ray_length1_d.y += self.ray_unitstep_size.y;
+
+            if self.dir.y < 0.0 {
+                (distance, Orientation::S)
+            } else {
+                (distance, Orientation::N)
+            }
+        }
+    }
+}
 
 #[inline(always)]
 pub fn calculate_ray(
@@ -12,88 +91,87 @@ pub fn calculate_ray(
     ray_dir_player: Vector2<f32>,
     theta: f32,
 ) -> (Intersection, Vec<Intersection>) {
-    let ray_dir_norm = Vector2::rotate(ray_dir_player, theta);
-    let ray_unitstep_size = Vector2::new(
-        (1.0 + (ray_dir_norm.y / ray_dir_norm.x) * (ray_dir_norm.y / ray_dir_norm.x)).sqrt(),
-        (1.0 + (ray_dir_norm.x / ray_dir_norm.y) * (ray_dir_norm.x / ray_dir_norm.y)).sqrt(),
-    );
-    let startv = ms.player.pos;
+    let mut ray_dir_norm = Vector2::rotate(ray_dir_player, theta);
+    // Where the current straight part of the ray starts and how far the ray went before it
+    let mut startv = ms.player.pos;
+    let mut travelled = 0.0;
+    let mut path = vec![];
+    let mut portals = 0;
 
-    let mut map_checkv = Vector2::new(startv.x.floor(), startv.y.floor());
-    let mut ray_length1_d = Vector2::new(0.0, 0.0);
+    let mut dda = Dda::new(startv, ray_dir_norm);
     let mut orientation = Orientation::N;
     let mut wall_type = Type::TiledFloor;
     let mut transparent_walls = vec![];
-    let mut stepv = Vector2::new(0.0, 0.0);
     let mut last_was_door = false;
 
-    if ray_dir_norm.x < 0.0 {
-        stepv.x = -1.0;
-        ray_length1_d.x = (startv.x - map_checkv.x) * ray_unitstep_size.x;
-    } else {
-        stepv.x = 1.0;
-        ray_length1_d.x = (map_checkv.x + 1.0 - startv.x) * ray_unitstep_size.x;
-    }
-
-    if ray_dir_norm.y < 0.0 {
-        stepv.y = -1.0;
-        ray_length1_d.y = (startv.y - map_checkv.y) * ray_unitstep_size.y;
-    } else {
-        stepv.y = 1.0;
-        ray_length1_d.y = (map_checkv.y + 1.0 - startv.y) * ray_unitstep_size.y;
-    }
-
     let mut tilefound = false;
     let mut distance = 0.0;
+    // Nothing can be seen through the fog past its end
+    let max_distance = match ms.atmosphere.reach {
+        Some(reach) => reach.min(MAX_DISTANCE) / theta.cos(),
+        None => MAX_DISTANCE,
+    };
 
-    while !tilefound && distance < 100.0 {
-        //arbitrary max distance
-
-        if ray_length1_d.x < ray_length1_d.y {
-            map_checkv.x += stepv.x;
-            distance = ray_length1_d.x;
-            ray_length1_d.x += ray_unitstep_size.x;
-
-            if ray_dir_norm.x < 0.0 {
-                orientation = Orientation::W;
-            } else {
-                orientation = Orientation::E;
-            }
-        } else {
-            map_checkv.y += stepv.y;
-            distance = ray_length1_d.y;
-            ray_length1_d.y += ray_unitstep_size.y;
-
-            if ray_dir_norm.y < 0.0 {
-                orientation = Orientation::S;
-            } else {
-                orientation = Orientation::N;
-            }
-        }
-        if map_checkv.x >= 0.0
-            && map_checkv.x < ms.map_size.0 as f32
-            && map_checkv.y >= 0.0
-            && map_checkv.y < ms.map_size.1 as f32
+    while !tilefound && travelled + distance < max_distance {
+        (distance, orientation) = dda.step();
+        if dda.map_checkv.x >= 0.0
+            && dda.map_checkv.x < ms.map_size.0 as f32
+            && dda.map_checkv.y >= 0.0
+            && dda.map_checkv.y < ms.map_size.1 as f32
         {
-            wall_type = ms.map.walls[map_checkv.y as usize * ms.map_size.0 + map_checkv.x as usize];
+            wall_type =
+                ms.map.walls[dda.map_checkv.y as usize * ms.map_size.0 + dda.map_checkv.x as usize];
 
             if last_was_door && wall_type as usize > 0 {
                 wall_type = Type::FrameWoodenDoor;
             }
             last_was_door = false;
+            let cell = dda.map_checkv.y as usize * ms.map_size.0 + dda.map_checkv.x as usize;
+            if let Some(portal) = ms.map.portals.get(&cell).filter(|_| portals < MAX_PORTALS) {
+                // Carry on from the same spot of the target cell, turned like the portal
+                if path.is_empty() {
+                    path.push(Segment::new(0.0, startv, ray_dir_norm, Bend::Start));
+                }
+                startv = portal.position(startv + ray_dir_norm * distance);
+                ray_dir_norm = portal.direction(ray_dir_norm);
+                travelled += distance;
+                distance = 0.0;
+                path.push(Segment::new(
+                    travelled,
+                    startv,
+                    ray_dir_norm,
+                    Bend::Portal(cell),
+                ));
+                // Start inside the target so it isn't entered again
+                dda = Dda::new(startv + ray_dir_norm * 1e-4, ray_dir_norm);
+                portals += 1;
+                continue;
+            }
+            if wall_type == Type::Mirror {
+                let limit = ms.map.mirror.bounces;
+                let ray = (startv, ray_dir_norm);
+                if let Some(ray) = bounce(&mut path, limit, travelled, ray, distance, orientation) {
+                    // Carry on from the mirror
+                    (startv, ray_dir_norm) = ray;
+                    travelled += distance;
+                    distance = 0.0;
+                    dda = Dda::new(startv, ray_dir_norm);
+                    continue;
+                }
+            }
             if wall_type == Type::WoodenDoor {
                 //door
                 let door_offset = ms
                     .map
                     .doors
-                    .get(&(map_checkv.y as usize * ms.map_size.0 + map_checkv.x as usize))
+                    .get(&(dda.map_checkv.y as usize * ms.map_size.0 + dda.map_checkv.x as usize))
                     .expect("error finding door")
                     .offset;
 
                 tilefound = true;
                 if orientation == Orientation::N || orientation == Orientation::S {
-                    if ray_length1_d.y - 0.5 * ray_unitstep_size.y <= ray_length1_d.x {
-                        distance = ray_length1_d.y - ray_unitstep_size.y * 0.5;
+                    if dda.ray_length1_d.y - 0.5 * dda.ray_unitstep_size.y <= dda.ray_length1_d.x {
+                        distance = dda.ray_length1_d.y - dda.ray_unitstep_size.y * 0.5;
 
                         if door_offset < 1.0 {
                             let pos_x = (startv.x + ray_dir_norm.x * distance).fract();
@@ -106,16 +184,17 @@ pub fn calculate_ray(
                         // side wall
                         if ray_dir_norm.x < 0.0 {
                             orientation = Orientation::W;
-                            map_checkv.x -= 1.0;
+                            dda.map_checkv.x -= 1.0;
                         } else {
                             orientation = Orientation::E;
-                            map_checkv.x += 1.0;
+                            dda.map_checkv.x += 1.0;
                         }
                         wall_type = Type::FrameWoodenDoor;
-                        distance = ray_length1_d.x;
+                        distance = dda.ray_length1_d.x;
                     }
-                } else if ray_length1_d.x - 0.5 * ray_unitstep_size.x <= ray_length1_d.y {
-                    distance = ray_length1_d.x - ray_unitstep_size.x * 0.5;
+                } else if dda.ray_length1_d.x - 0.5 * dda.ray_unitstep_size.x <= dda.ray_length1_d.y
+                {
+                    distance = dda.ray_length1_d.x - dda.ray_unitstep_size.x * 0.5;
                     if door_offset < 1.0 {
                         let pos_y = (startv.y + ray_dir_norm.y * distance).fract();
                         if pos_y > door_offset * 0.5 && 1.0 - pos_y > door_offset * 0.5 {
@@ -126,19 +205,19 @@ pub fn calculate_ray(
                 } else {
                     if ray_dir_norm.y < 0.0 {
                         orientation = Orientation::S;
-                        map_checkv.y -= 1.0;
+                        dda.map_checkv.y -= 1.0;
                     } else {
                         orientation = Orientation::N;
-                        map_checkv.y += 1.0;
+                        dda.map_checkv.y += 1.0;
                     }
                     wall_type = Type::FrameWoodenDoor;
-                    distance = ray_length1_d.y;
+                    distance = dda.ray_length1_d.y;
                 }
             } else if wall_type == Type::Cowbeb || wall_type == Type::MetalBars {
                 let mut offset = 0.0;
                 if orientation == Orientation::N || orientation == Orientation::S {
-                    if ray_length1_d.y - 0.5 * ray_unitstep_size.y <= ray_length1_d.x {
-                        distance = ray_length1_d.y - ray_unitstep_size.y * 0.5;
+                    if dda.ray_length1_d.y - 0.5 * dda.ray_unitstep_size.y <= dda.ray_length1_d.x {
+                        distance = dda.ray_length1_d.y - dda.ray_unitstep_size.y * 0.5;
                     } else {
                         if ray_dir_norm.x < 0.0 {
                             orientation = Orientation::W;
@@ -147,21 +226,23 @@ pub fn calculate_ray(
                             orientation = Orientation::E;
                             offset = 1.0;
                         }
-                        distance = ray_length1_d.x;
+                        distance = dda.ray_length1_d.x;
                     }
                     transparent_walls.push(Intersection::new(
                         (startv + ray_dir_norm * distance).to_array(),
-                        distance,
-                        (map_checkv.y) as usize * ms.map_size.0 + (map_checkv.x + offset) as usize,
+                        travelled + distance,
+                        (dda.map_checkv.y) as usize * ms.map_size.0
+                            + (dda.map_checkv.x + offset) as usize,
                         orientation,
-                        ms.map.walls[(map_checkv.y) as usize * ms.map_size.0
-                            + (map_checkv.x + offset) as usize] as usize,
+                        ms.map.walls[(dda.map_checkv.y) as usize * ms.map_size.0
+                            + (dda.map_checkv.x + offset) as usize]
+                            as usize,
                         true,
                         false,
                     ));
                 } else {
-                    if ray_length1_d.x - 0.5 * ray_unitstep_size.x <= ray_length1_d.y {
-                        distance = ray_length1_d.x - ray_unitstep_size.x * 0.5;
+                    if dda.ray_length1_d.x - 0.5 * dda.ray_unitstep_size.x <= dda.ray_length1_d.y {
+                        distance = dda.ray_length1_d.x - dda.ray_unitstep_size.x * 0.5;
                     } else {
                         if ray_dir_norm.y < 0.0 {
                             orientation = Orientation::S;
@@ -170,45 +251,50 @@ pub fn calculate_ray(
                             orientation = Orientation::N;
                             offset = 1.0;
                         }
-                        distance = ray_length1_d.y;
+                        distance = dda.ray_length1_d.y;
                     }
                     transparent_walls.push(Intersection::new(
                         (startv + ray_dir_norm * distance).to_array(),
-                        distance,
-                        (map_checkv.y + offset) as usize * ms.map_size.0 + (map_checkv.x) as usize,
+                        travelled + distance,
+                        (dda.map_checkv.y + offset) as usize * ms.map_size.0
+                            + (dda.map_checkv.x) as usize,
                         orientation,
-                        ms.map.walls[(map_checkv.y + offset) as usize * ms.map_size.0
-                            + (map_checkv.x) as usize] as usize,
+                        ms.map.walls[(dda.map_checkv.y + offset) as usize * ms.map_size.0
+                            + (dda.map_checkv.x) as usize] as usize,
                         true,
                         false,
                     ));
                 }
             } else if wall_type == Type::Stairs {
-                transparent_walls.push(Intersection::new(
-                    (startv + ray_dir_norm * distance).to_array(),
-                    distance,
-                    (map_checkv.y) as usize * ms.map_size.0 + (map_checkv.x) as usize,
-                    orientation,
-                    wall_type as usize,
-                    false,
-                    false,
-                ));
+                // Steps are laid out from the player's position, so rays bent by mirrors or
+                // portals go over stairs and their reflections and portal views leave them out
+                if path.is_empty() {
+                    transparent_walls.push(Intersection::new(
+                        (startv + ray_dir_norm * distance).to_array(),
+                        travelled + distance,
+                        (dda.map_checkv.y) as usize * ms.map_size.0 + (dda.map_checkv.x) as usize,
+                        orientation,
+                        wall_type as usize,
+                        false,
+                        false,
+                    ));
+                }
             } else if wall_type as usize > 0 && wall_type != Type::Stairs2 {
                 tilefound = true;
             }
             if ((orientation == Orientation::W || orientation == Orientation::E)
-                && ms.map.walls
-                    [map_checkv.y as usize * ms.map_size.0 + (map_checkv.x - stepv.x) as usize]
+                && ms.map.walls[dda.map_checkv.y as usize * ms.map_size.0
+                    + (dda.map_checkv.x - dda.stepv.x) as usize]
                     == Type::WoodenDoor)
                 || ((orientation == Orientation::N || orientation == Orientation::S)
-                    && ms.map.walls
-                        [(map_checkv.y - stepv.y) as usize * ms.map_size.0 + map_checkv.x as usize]
+                    && ms.map.walls[(dda.map_checkv.y - dda.stepv.y) as usize * ms.map_size.0
+                        + dda.map_checkv.x as usize]
                         == Type::WoodenDoor)
             {
                 wall_type = Type::FrameWoodenDoor;
             }
 
-            if ms.player.current_wall == Type::Stairs {
+            if ms.player.current_wall == Type::Stairs && path.is_empty() {
                 let m = ray_dir_norm.y / ray_dir_norm.x;
                 let pos_x0 = ms.player.pos.x.floor();
                 let iy = m * (pos_x0 - ms.player.pos.x) + ms.player.pos.y; // intersection y with the first step
@@ -230,44 +316,72 @@ pub fn calculate_ray(
     }
     let int_point = startv + ray_dir_norm * distance;
     let is_up = int_point.x > 32.0;
-    (
-        Intersection::new(
-            int_point.to_array(),
-            distance,
-            map_checkv.y as usize * ms.map_size.0 + map_checkv.x as usize,
-            orientation,
-            wall_type as usize,
-            false,
-            is_up,
-        ),
-        transparent_walls,
-    )
+    let mut intersection = Intersection::new(
+        int_point.to_array(),
+        travelled + distance,
+        dda.map_checkv.y as usize * ms.map_size.0 + dda.map_checkv.x as usize,
+        orientation,
+        wall_type as usize,
+        false,
+        is_up,
+    );
+    intersection.path = path;
+    (intersection, transparent_walls)
 }
 
-#[inline(always)]
-pub fn draw_slice(ms: &MainState, slice: &mut [u8], j: usize, h: f32) {
-    let (intersection, transparent_walls) = calculate_ray(ms, ms.player.dir_norm, ms.angles[j]);
+/// Wall face in the middle of the view, the texture position and the world point there with
+/// its height in walls. `None` when looking at the floor, the ceiling or past the walls of the
+/// ground storey
+pub fn aim(ms: &MainState) -> Option<(Surface, [f32; 2], [f32; 3])> {
+    let (intersection, _) = calculate_ray(ms, ms.player.dir_norm, 0.0);
+    if intersection.wall_type == 0 || intersection.is_up {
+        return None;
+    }
+    // Row of the middle of the screen on a wall spanning the ground storey
+    let ty = ms.cell_size * 0.5
+        - (ms.player.jump + ms.player.pitch * intersection.distance) * ms.cell_size
+            / ms.player.planedist;
+    (0.0..ms.cell_size).contains(&ty).then(|| {
+        (
+            Surface::Wall(intersection.map_checkv, intersection.orientation),
+            [intersection.texture_x(ms.cell_size), ty],
+            [
+                intersection.point[0],
+                intersection.point[1],
+                1.0 - ty / ms.cell_size,
+            ],
+        )
+    })
+}
 
+/// Draws column `j` from the ray cast for it by `calculate_ray`
+#[inline(always)]
+pub fn draw_slice(
+    ms: &MainState,
+    column: &mut Column,
+    j: usize,
+    h: f32,
+    (intersection, transparent_walls): &(Intersection, Vec<Intersection>),
+    views: &Views,
+) {
     let corrected_distance = intersection.distance * ms.angles[j].cos();
-    let pos_z = ms.player.jump / corrected_distance + ms.player.pitch;
-
-    let rect_h = (ms.player.planedist / corrected_distance * 100.0).round() / 100.0;
-    let rect_ceiling = -rect_h + (h - rect_h) * 0.5;
-    let mut rect_floor = (h + rect_h) * 0.5;
+    let (pos_z, rect_h, [rect_ceiling, mut rect_floor]) = wall_span(ms, h, corrected_distance);
     let mut floor_height = ms.player.planedist + 2.0 * ms.player.jump;
     let ceiling_height = -3.0 * ms.player.planedist + 2.0 * ms.player.jump;
 
-    if ms.player.jump * 2.0 > ms.player.planedist && intersection.is_up {
+    // Looking down from the upper storey at a wall that only stands on it
+    let looking_down = ms.player.jump * 2.0 > ms.player.planedist && intersection.is_up;
+    if looking_down {
         rect_floor = (rect_floor - rect_h).max(-pos_z);
         floor_height -= 2.0 * ms.player.planedist;
     } else {
-        draw_wall(ms, slice, h, &intersection, h * 0.5, rect_h, &pos_z);
+        draw_wall(ms, column, h, intersection, h * 0.5, rect_h, &pos_z);
     }
     draw_wall(
         ms,
-        slice,
+        column,
         h,
-        &intersection,
+        intersection,
         h * 0.5 - ms.player.planedist / corrected_distance,
         rect_h,
         &(ms.player.jump / corrected_distance + ms.player.pitch),
@@ -281,192 +395,202 @@ pub fn draw_slice(ms: &MainState, slice: &mut [u8], j: usize, h: f32) {
                 y,
                 floor_height,
                 ms,
-                slice,
-                &intersection,
+                column,
+                intersection,
                 corrected_distance,
                 None,
             );
         }
     }
+    // The wall shows in the polished floor drawn in front of it
+    if !looking_down {
+        reflect_wall(
+            ms,
+            column,
+            intersection,
+            corrected_distance,
+            floor_height,
+            pos_z + rect_floor,
+            rect_h,
+        );
+    }
     //draw ceiling
     let rect_top_draw = rect_ceiling.min(h - pos_z);
+    // The sky panorama repeats every FOV degrees
+    let ray_angle = ms.player.dir_norm.angle() + ms.angles[j];
+    let sky_x = (((ray_angle / FOV.to_radians()).rem_euclid(1.0) * ms.screen.sky_size.0 as f32)
+        as usize)
+        .min(ms.screen.sky_size.0 - 1);
 
     for y in 0..(rect_top_draw + pos_z) as usize {
-        draw_floor(
+        draw_ceiling(
             y,
             ceiling_height,
             ms,
-            slice,
-            &intersection,
+            column,
+            intersection,
             corrected_distance,
-            Some(Type::TiledCeiling as usize),
+            sky_x,
+            h,
         );
-        //ms.screen.draw_pixel(slice, y as usize, &[0, 0, 0, 0]);
-    }
-    if !&transparent_walls.is_empty() {
-        let mut twandsp = transparent_walls
-            .into_iter()
-            .map(TWandSprites::TW)
-            .collect::<Vec<_>>();
-        twandsp.extend(ms.sprites.iter().map(TWandSprites::Sprites));
-
-        twandsp.sort_by(|a, b| {
-            b.distance2()
-                .partial_cmp(&a.distance2())
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
-        twandsp.iter().for_each(|e| {
-            match e {
-                TWandSprites::Sprites(sprite) => {
-                    sprite.draw(slice, &ms.player, j, &ms.screen, corrected_distance)
-                }
-                TWandSprites::TW(tw) => {
-                    let tw_corrected_distance = tw.distance * ms.angles[j].cos();
-                    if tw.wall_type == 10 || tw.wall_type == 11 {
+    }
+    // Stairs and see-through walls are depth tested like sprites, so no sorting is needed
+    for tw in transparent_walls.iter() {
+        let tw_corrected_distance = tw.distance * ms.angles[j].cos();
+        if tw.wall_type == 10 || tw.wall_type == 11 {
+            draw_wall(
+                ms,
+                column,
+                h,
+                tw,
+                h * 0.5,
+                ms.player.planedist / tw_corrected_distance,
+                &(ms.player.pitch + ms.player.jump / tw_corrected_distance),
+            )
+        } else if tw.wall_type == 12
+            && (tw.orientation == Orientation::W || tw.orientation == Orientation::E)
+        {
+            let m = (ms.player.pos.y - tw.point[1]) / (ms.player.pos.x - tw.point[0]); //slope of the line
+            let iy = m * (tw.point[0] + 1.0 / 4.0 - ms.player.pos.x) + ms.player.pos.y; // intersection y with the first step
+            let delta_distance =
+                (1.0 / (4.0 * 4.0) + (iy - tw.point[1]) * (iy - tw.point[1])).sqrt(); // distance between steps
+            let start = if ms.player.current_wall == Type::Stairs {
+                (ms.player.pos.x.fract() * 4.0).ceil() as usize
+            } else {
+                1
+            };
+            for i in (start..8).rev() {
+                let tw2 = Intersection::new(
+                    [
+                        tw.point[0] + 1.0 * (i as f32) / 4.0,
+                        m * (tw.point[0] + 1.0 * (i as f32) / 4.0 - ms.player.pos.x)
+                            + ms.player.pos.y,
+                    ],
+                    tw.distance + delta_distance * (i as f32),
+                    tw.map_checkv,
+                    Orientation::E,
+                    12,
+                    false,
+                    false,
+                );
+                if tw2.point[1] > tw.point[1] - 5.0 && tw2.point[1] < tw.point[1] + 5.0 {
+                    if ms.map.walls[tw2.point[0] as usize + tw2.point[1] as usize * ms.map_size.0]
+                        == Type::Stairs
+                        || ms.map.walls
+                            [tw2.point[0] as usize + tw2.point[1] as usize * ms.map_size.0]
+                            == Type::Stairs2
+                    {
                         draw_wall(
                             ms,
-                            slice,
+                            column,
                             h,
-                            tw,
-                            h * 0.5,
-                            ms.player.planedist / tw_corrected_distance,
-                            &(ms.player.pitch + ms.player.jump / tw_corrected_distance),
-                        )
-                    } else if tw.wall_type == 12
-                        && (tw.orientation == Orientation::W || tw.orientation == Orientation::E)
+                            &tw2,
+                            h * 0.5
+                                + ms.player.planedist / (tw2.distance * ms.angles[j].cos())
+                                    * ((3.0 - i as f32) / 8.0 + 1.0 / 16.0),
+                            1.0 / 8.0 * ms.player.planedist / (tw2.distance * ms.angles[j].cos()),
+                            &(ms.player.pitch
+                                + ms.player.jump / (tw2.distance * ms.angles[j].cos())),
+                        );
+                        for y in (ms.player.pitch
+                            + ms.player.jump / (tw2.distance * ms.angles[j].cos())
+                            + h * 0.5
+                            + ms.player.planedist / (tw2.distance * ms.angles[j].cos())
+                                * ((3.0 - i as f32) / 8.0 + 1.0 / 8.0))
+                            as usize
+                            ..(ms.player.pitch
+                                + ms.player.jump
+                                    / ((tw2.distance - delta_distance) * ms.angles[j].cos())
+                                + h * 0.5
+                                + ms.player.planedist
+                                    / ((tw2.distance - delta_distance) * ms.angles[j].cos())
+                                    * ((4.0 - i as f32) / 8.0))
+                                .min(h) as usize
+                        {
+                            draw_floor(
+                                y,
+                                ms.player.planedist * (1.0 - (i as f32) / 4.0)
+                                    + 2.0 * ms.player.jump,
+                                ms,
+                                column,
+                                intersection,
+                                corrected_distance,
+                                Some(ms.map.floors[tw.map_checkv]),
+                            );
+                        }
+                    } else if ms.player.planedist * (1.0 - (i as f32) / 4.0)
+                        > -2.0 * (ms.player.jump)
                     {
-                        let m = (ms.player.pos.y - tw.point[1]) / (ms.player.pos.x - tw.point[0]); //slope of the line
-                        let iy = m * (tw.point[0] + 1.0 / 4.0 - ms.player.pos.x) + ms.player.pos.y; // intersection y with the first step
-                        let delta_distance =
-                            (1.0 / (4.0 * 4.0) + (iy - tw.point[1]) * (iy - tw.point[1])).sqrt(); // distance between steps
-                        let start = if ms.player.current_wall == Type::Stairs {
-                            (ms.player.pos.x.fract() * 4.0).ceil() as usize
-                        } else {
-                            1
-                        };
-                        for i in (start..8).rev() {
-                            let tw2 = Intersection::new(
-                                [
-                                    tw.point[0] + 1.0 * (i as f32) / 4.0,
-                                    m * (tw.point[0] + 1.0 * (i as f32) / 4.0 - ms.player.pos.x)
-                                        + ms.player.pos.y,
-                                ],
-                                tw.distance + delta_distance * (i as f32),
-                                tw.map_checkv,
-                                Orientation::E,
-                                12,
-                                false,
-                                false,
+                        for y in (pos_z + h * 0.5 + rect_h * (4.0 - i as f32) / 8.0) as usize
+                            ..(ms.player.pitch
+                                + ms.player.jump
+                                    / ((tw2.distance - delta_distance) * ms.angles[j].cos())
+                                + h * 0.5
+                                + ms.player.planedist
+                                    / ((tw2.distance - delta_distance) * ms.angles[j].cos())
+                                    * ((4.0 - i as f32) / 8.0))
+                                .min(h) as usize
+                        {
+                            draw_floor(
+                                y,
+                                ms.player.planedist * (1.0 - (i as f32) / 4.0)
+                                    + 2.0 * ms.player.jump,
+                                ms,
+                                column,
+                                intersection,
+                                corrected_distance,
+                                Some(ms.map.floors[tw.map_checkv]),
                             );
-                            if tw2.point[1] > tw.point[1] - 5.0 && tw2.point[1] < tw.point[1] + 5.0
-                            {
-                                if ms.map.walls
-                                    [tw2.point[0] as usize + tw2.point[1] as usize * ms.map_size.0]
-                                    == Type::Stairs
-                                    || ms.map.walls[tw2.point[0] as usize
-                                        + tw2.point[1] as usize * ms.map_size.0]
-                                        == Type::Stairs2
-                                {
-                                    draw_wall(
-                                        ms,
-                                        slice,
-                                        h,
-                                        &tw2,
-                                        h * 0.5
-                                            + ms.player.planedist
-                                                / (tw2.distance * ms.angles[j].cos())
-                                                * ((3.0 - i as f32) / 8.0 + 1.0 / 16.0),
-                                        1.0 / 8.0 * ms.player.planedist
-                                            / (tw2.distance * ms.angles[j].cos()),
-                                        &(ms.player.pitch
-                                            + ms.player.jump / (tw2.distance * ms.angles[j].cos())),
-                                    );
-                                    for y in (ms.player.pitch
-                                        + ms.player.jump / (tw2.distance * ms.angles[j].cos())
-                                        + h * 0.5
-                                        + ms.player.planedist / (tw2.distance * ms.angles[j].cos())
-                                            * ((3.0 - i as f32) / 8.0 + 1.0 / 8.0))
-                                        as usize
-                                        ..(ms.player.pitch
-                                            + ms.player.jump
-                                                / ((tw2.distance - delta_distance)
-                                                    * ms.angles[j].cos())
-                                            + h * 0.5
-                                            + ms.player.planedist
-                                                / ((tw2.distance - delta_distance)
-                                                    * ms.angles[j].cos())
-                                                * ((4.0 - i as f32) / 8.0))
-                                            .min(h)
-                                            as usize
-                                    {
-                                        draw_floor(
-                                            y,
-                                            ms.player.planedist * (1.0 - (i as f32) / 4.0)
-                                                + 2.0 * ms.player.jump,
-                                            ms,
-                                            slice,
-                                            &intersection,
-                                            corrected_distance,
-                                            Some(Type::TiledFloor as usize),
-                                        );
-                                    }
-                                
-                                } else if ms.player.planedist * (1.0 - (i as f32) / 4.0)
-                                    > -2.0 * (ms.player.jump){
-                                
-                                    for y in (pos_z + h * 0.5 + rect_h * (4.0 - i as f32) / 8.0)
-                                        as usize
-                                        ..(ms.player.pitch
-                                            + ms.player.jump
-                                                / ((tw2.distance - delta_distance)
-                                                    * ms.angles[j].cos())
-                                            + h * 0.5
-                                            + ms.player.planedist
-                                                / ((tw2.distance - delta_distance)
-                                                    * ms.angles[j].cos())
-                                                * ((4.0 - i as f32) / 8.0))
-                                            .min(h)
-                                            as usize
-                                    {
-                                        draw_floor(
-                                            y,
-                                            ms.player.planedist * (1.0 - (i as f32) / 4.0)
-                                                + 2.0 * ms.player.jump,
-                                            ms,
-                                            slice,
-                                            &intersection,
-                                            corrected_distance,
-                                            Some(Type::TiledFloor as usize),
-                                        );
-                                    }
-                                }
-                            }
-                            
                         }
-                        draw_wall(
-                            ms,
-                            slice,
-                            h,
-                            tw,
-                            h * 0.5 + ms.player.planedist / tw_corrected_distance * 7.0 / 16.0,
-                            1.0 / 8.0 * ms.player.planedist / tw_corrected_distance,
-                            &(ms.player.pitch + ms.player.jump / tw_corrected_distance),
-                        );
                     }
                 }
             }
-        });
-    } else {
-        ms.sprites
-            .iter()
-            .for_each(|sprite| sprite.draw(slice, &ms.player, j, &ms.screen, corrected_distance));
+            draw_wall(
+                ms,
+                column,
+                h,
+                tw,
+                h * 0.5 + ms.player.planedist / tw_corrected_distance * 7.0 / 16.0,
+                1.0 / 8.0 * ms.player.planedist / tw_corrected_distance,
+                &(ms.player.pitch + ms.player.jump / tw_corrected_distance),
+            );
+        }
+    }
+    // Sprites and particles past mirrors and portals are drawn where they are seen in them
+    column.look(views, &intersection.path);
+    ms.sprites
+        .iter()
+        .for_each(|sprite| sprite.draw(column, &ms.player, j, &ms.screen, &ms.atmosphere));
+    ms.particles.draw(column, j);
+    // Everything drawn past a mirror within its outline is its reflection
+    for segment in intersection
+        .path
+        .iter()
+        .filter(|segment| matches!(segment.bend, Bend::Mirror(_)))
+    {
+        let corrected = segment.distance * ms.angles[j].cos();
+        let (pos_z, _, [top, bottom]) = wall_span(ms, h, corrected);
+        column.reflect([pos_z + top, pos_z + bottom], corrected, ms.map.mirror.tint);
     }
 }
 
+/// Screen offset of walls `corrected` deep, the height of a storey of them and the rows of the
+/// top of the upper storey and the bottom of the ground one without the offset
+#[inline(always)]
+fn wall_span(ms: &MainState, h: f32, corrected: f32) -> (f32, f32, [f32; 2]) {
+    let pos_z = ms.player.jump / corrected + ms.player.pitch;
+    let rect_h = (ms.player.planedist / corrected * 100.0).round() / 100.0;
+    (
+        pos_z,
+        rect_h,
+        [-rect_h + (h - rect_h) * 0.5, (h + rect_h) * 0.5],
+    )
+}
+
 #[inline(always)]
 fn draw_wall(
     ms: &MainState,
-    slice: &mut [u8],
+    column: &mut Column,
     h: f32,
     intersection: &Intersection,
     center: f32,
@@ -511,18 +635,7 @@ fn draw_wall(
         }
     };
 
-    let mut tx = match intersection.orientation {
-        Orientation::N => {
-            let tx_temp = inter_x * ms.cell_size;
-            ms.cell_size - 1.0 - tx_temp.floor()
-        }
-        Orientation::E => inter_y * ms.cell_size,
-        Orientation::S => inter_x * ms.cell_size,
-        Orientation::W => {
-            let tx_temp = inter_y * ms.cell_size;
-            ms.cell_size - 1.0 - tx_temp.floor()
-        }
-    };
+    let mut tx = intersection.texture_x(ms.cell_size);
 
     if intersection.wall_type == 6 {
         let offset = 1.0
@@ -562,12 +675,35 @@ fn draw_wall(
             }
         }
     }
-    let mut wall_type = intersection.wall_type;
-    if wall_type == 8 && up && !intersection.is_up {
-        wall_type = 4;
-    }
+    let texture_cell = if intersection.wall_type == Type::FrameWoodenDoor as usize {
+        intersection.behind(ms.map_size.0)
+    } else {
+        intersection.map_checkv
+    };
+    let texture = ms.map.wall_texture(
+        intersection.wall_type,
+        texture_cell,
+        up && !intersection.is_up,
+    );
+    let frame = ms.screen.atlas.frame(texture, ms.time);
+    // Walls are fogged like the cell their face looks into
+    let fog = ms.atmosphere.at(intersection.behind(ms.map_size.0));
+    let level = ms.screen.level(texture, ty_step);
+    let depth = column.depth_of(intersection.distance);
+    // Stair steps and see-through walls don't meet the floor like full walls
+    let occluded = intersection.wall_type != 12 && !intersection.is_transparent;
+    let relief = ms.screen.atlas.get(texture).normal.map(|_| Relief {
+        basis: wall_basis(&intersection.orientation),
+        light: ms
+            .lighting_1
+            .get_direction_wall(intersection.map_checkv + z, &intersection.orientation),
+    });
+    let decals = ms.decals.on(Surface::Wall(
+        intersection.map_checkv + z,
+        intersection.orientation,
+    ));
 
-    for y in  (pos_z + rect_top) as usize..(pos_z + rect_bottom_draw) as usize{
+    for y in (pos_z + rect_top) as usize..(pos_z + rect_bottom_draw) as usize {
         //TODO: FIX THIS FLOAT POINT ROUNDING ERROR
         if ty >= 128.0 {
             dbg!(
@@ -582,76 +718,571 @@ fn draw_wall(
             ty = 127.0;
         }
 
-        let texture_position = unsafe {
-            [
-                tx.to_int_unchecked::<usize>(),
-                wall_type * 128 + ty.to_int_unchecked::<usize>(),
-            ]
-        };
-        let shade = unsafe {
-            ms.torch.intensity
-                * ms.lighting_1.get_lighting_wall(
+        if column.visible(y, depth) {
+            let sample = Sample::new(texture, frame, level, [tx, ty]);
+            let mut shade = unsafe {
+                ms.lighting_1.get_lighting_wall(
                     tx,
                     ty * 3.0, //*3.0/128.0
                     intersection.map_checkv + z,
                     &intersection.orientation,
                     up,
                 )
-        };
-        let flashlight = ffmin(3.0 / (intersection.distance * intersection.distance), 1.5);
+            };
+            if occluded {
+                let ao = ms.occlusion.wall(
+                    tx / ms.cell_size,
+                    1.0 - ty / ms.cell_size,
+                    intersection.map_checkv,
+                    &intersection.orientation,
+                    up,
+                );
+                shade = shade.map(|l| l * ao);
+            }
+            let flashlight = ms.player.flashlight.light(
+                intersection.distance,
+                column.view_offset(y, ms.player.planedist),
+            );
 
-        if intersection.is_transparent {
-            ms.screen
-                .draw_transparent_texture(slice, texture_position, y, shade, flashlight);
-        } else {
-            ms.screen
-                .draw_texture(slice, texture_position, y, shade, flashlight);
+            // Only the opaque texels of see-through walls hide what's behind them
+            let drawn = if intersection.is_transparent {
+                ms.screen.draw_transparent_texture(
+                    column.pixels,
+                    sample,
+                    y,
+                    shade,
+                    flashlight,
+                    depth,
+                    fog,
+                    relief,
+                )
+            } else {
+                ms.screen.draw_texture(
+                    column.pixels,
+                    sample,
+                    y,
+                    shade,
+                    flashlight,
+                    depth,
+                    fog,
+                    relief,
+                );
+                true
+            };
+            if drawn {
+                column.depth[y] = depth;
+                for decal in decals {
+                    if let Some(position) = decal.at([tx, ty]) {
+                        let level = ms
+                            .screen
+                            .level(decal.texture, ty_step * UNITS as f32 / decal.size);
+                        let sample = Sample::new(decal.texture, 0, level, position);
+                        ms.screen.draw_decal(
+                            column.pixels,
+                            sample,
+                            y,
+                            shade,
+                            flashlight,
+                            depth,
+                            fog,
+                        );
+                    }
+                }
+            }
         }
         ty += ty_step;
     }
 }
 
 #[inline(always)]
-fn draw_floor(
+fn floor_position(
     y: usize,
     height: f32,
     ms: &MainState,
-    slice: &mut [u8],
     intersection: &Intersection,
     corrected_distance: f32,
-    texture: Option<usize>,
-) {
+) -> (f32, f32, f32, usize) {
     let denominator = ms.buffer_floors[y]; // Use a buffer since they're always the same values
     let current_dist = height * denominator;
     let weight = current_dist / corrected_distance;
 
-    let rhs = ms.player.pos * (1.0 - weight);
-
-    let current_floor_x = weight * intersection.point[0] + rhs.x;
-    let current_floor_y = weight * intersection.point[1] + rhs.y;
+    let (current_floor_x, current_floor_y) =
+        match intersection.point_at(weight * intersection.distance) {
+            Some(point) => (point.x, point.y),
+            None => {
+                let rhs = ms.player.pos * (1.0 - weight);
+                (
+                    weight * intersection.point[0] + rhs.x,
+                    weight * intersection.point[1] + rhs.y,
+                )
+            }
+        };
 
     let location = unsafe {
         current_floor_x.to_int_unchecked::<usize>()
             + current_floor_y.to_int_unchecked::<usize>() * ms.map_size.0
     }; //Cant be negative
+    (current_floor_x, current_floor_y, current_dist, location)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[inline(always)]
+fn draw_ceiling(
+    y: usize,
+    height: f32,
+    ms: &MainState,
+    column: &mut Column,
+    intersection: &Intersection,
+    corrected_distance: f32,
+    sky_x: usize,
+    h: f32,
+) {
+    let (current_floor_x, current_floor_y, current_dist, location) =
+        floor_position(y, height, ms, intersection, corrected_distance);
+    if ms.map.open_sky[location] {
+        // Same texels per radian vertically as horizontally, with the horizon at the bottom row
+        let sky_scale = ms.screen.sky_size.0 as f32 / (FOV.to_radians() * ms.player.planedist);
+        let sky_y =
+            ms.screen.sky_size.1 as f32 + (y as f32 - h * 0.5 - ms.player.pitch) * sky_scale;
+        let sky_y = num::clamp(sky_y, 0.0, ms.screen.sky_size.1 as f32 - 1.0) as usize;
+        ms.screen.draw_sky(column.pixels, [sky_x, sky_y], y);
+    } else {
+        // The ceiling sits on top of the second storey, so it's lit by the upper lighting layer
+        draw_floor_texel(
+            ms,
+            column,
+            y,
+            [current_floor_x, current_floor_y],
+            current_dist,
+            ms.map.ceilings[location],
+            location + ms.map_size.0 * ms.map_size.1,
+            [0.0; 3],
+        );
+    }
+}
+
+#[inline(always)]
+fn draw_floor(
+    y: usize,
+    height: f32,
+    ms: &MainState,
+    column: &mut Column,
+    intersection: &Intersection,
+    corrected_distance: f32,
+    texture: Option<usize>,
+) {
+    let (mut current_floor_x, mut current_floor_y, mut current_dist, mut location) =
+        floor_position(y, height, ms, intersection, corrected_distance);
+    let mut glow = [0.0; 3];
+    if let Some(liquid) = ms.map.liquids[location].filter(|_| texture.is_none()) {
+        // Liquids sit below the floor, so the pool's far bank hides the edge of the surface
+        let lowered = floor_position(
+            y,
+            height + 2.0 * liquid.depth * ms.player.planedist,
+            ms,
+            intersection,
+            corrected_distance,
+        );
+        // Past the pool's edge the lowered point lands on dry floor, which the bank hides
+        if lowered.2.abs() < corrected_distance && ms.map.liquids[lowered.3].is_some() {
+            (current_floor_x, current_floor_y, current_dist, location) = lowered;
+        }
+        let [x, y] = liquid.ripple(
+            [
+                current_floor_x * UNITS as f32,
+                current_floor_y * UNITS as f32,
+            ],
+            ms.time,
+        );
+        (current_floor_x, current_floor_y) = (x / UNITS as f32, y / UNITS as f32);
+        glow = liquid.glow;
+    }
     let floor_type = if let Some(tex) = texture {
         tex
     } else {
         ms.map.floors[location]
     };
+    draw_floor_texel(
+        ms,
+        column,
+        y,
+        [current_floor_x, current_floor_y],
+        current_dist,
+        floor_type,
+        location,
+        glow,
+    );
+}
 
-    let ftx = unsafe { (current_floor_x * 128.0).to_int_unchecked::<usize>() % 128 }; //Cant be negative
-    let fty = unsafe { (current_floor_y * 128.0).to_int_unchecked::<usize>() % 128 }; //Cant be negative
-    let lighting = ms
+/// Draws a texel of a floor or ceiling, `glow` is light the surface gives off itself
+#[allow(clippy::too_many_arguments)]
+#[inline(always)]
+fn draw_floor_texel(
+    ms: &MainState,
+    column: &mut Column,
+    y: usize,
+    point: [f32; 2],
+    current_dist: f32,
+    floor_type: usize,
+    light_location: usize,
+    glow: [f32; 3],
+) {
+    if !column.visible(y, current_dist.abs()) {
+        return;
+    }
+    let ftx = point[0].fract() * UNITS as f32; //Cant be negative
+    let fty = point[1].fract() * UNITS as f32; //Cant be negative
+    let ao = ms
+        .occlusion
+        .floor(point[0].fract(), point[1].fract(), light_location);
+    let mut lighting = ms
         .lighting_1
-        .get_lighting_floor(ftx as f32, fty as f32, location);
+        .get_lighting_floor(ftx, fty, light_location)
+        .map(|l| l * ao);
+    lighting.iter_mut().zip(glow).for_each(|(l, g)| *l += g);
+    // A pixel spans more of the floor in depth than across the column close to the horizon
+    let across = current_dist.abs() * RAYSPERPIXEL as f32 / ms.player.planedist;
+    let depth = 2.0 * (current_dist * ms.buffer_floors[y]).abs();
+    let footprint = across.max(depth) * UNITS as f32;
+    let sample = Sample::new(
+        floor_type,
+        ms.screen.atlas.frame(floor_type, ms.time),
+        ms.screen.level(floor_type, footprint),
+        [ftx, fty],
+    );
+    // Ceilings are lit from the upper layer and face down
+    let up = if light_location < ms.map_size.0 * ms.map_size.1 {
+        1.0
+    } else {
+        -1.0
+    };
+    let relief = ms.screen.atlas.get(floor_type).normal.map(|_| Relief {
+        basis: [[1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, up]],
+        light: ms.lighting_1.get_direction_floor(light_location),
+    });
+    let flashlight = ms.player.flashlight.light(
+        current_dist.abs(),
+        column.view_offset(y, ms.player.planedist),
+    );
+    let fog = ms.atmosphere.at(light_location);
     ms.screen.draw_texture(
-        slice,
-        [ftx, (floor_type * 128) + fty],
+        column.pixels,
+        sample,
         y,
-        ms.torch.intensity * lighting,
-        ffmin(3.0 / (current_dist * current_dist), 1.5),
-    )
+        lighting,
+        flashlight,
+        current_dist.abs(),
+        fog,
+        relief,
+    );
+    for decal in ms.decals.on(Surface::Floor(light_location)) {
+        if let Some(position) = decal.at([ftx, fty]) {
+            let level = ms
+                .screen
+                .level(decal.texture, footprint * UNITS as f32 / decal.size);
+            let sample = Sample::new(decal.texture, 0, level, position);
+            ms.screen.draw_decal(
+                column.pixels,
+                sample,
+                y,
+                lighting,
+                flashlight,
+                current_dist.abs(),
+                fog,
+            );
+        }
+    }
+    column.depth[y] = current_dist.abs();
+}
+
+/// Blends a fading upside down copy of the wall into the polished floor in front of it,
+/// `bottom` is the screen row where the wall meets the floor
+#[inline(always)]
+fn reflect_wall(
+    ms: &MainState,
+    column: &mut Column,
+    intersection: &Intersection,
+    corrected_distance: f32,
+    height: f32,
+    bottom: f32,
+    rect_h: f32,
+) {
+    column.mirror_floor(bottom, rect_h, |y| {
+        let (_, _, _, location) = floor_position(y, height, ms, intersection, corrected_distance);
+        ms.screen.atlas.get(ms.map.floors[location]).reflect
+    });
+}
+
+/// Turns a ray that hit a mirror `distance` along its straight part back across the mirror's
+/// face and records the bend in `path`. `None` once it bounced off `limit` mirrors, the
+/// mirror is then drawn as a wall
+#[inline(always)]
+fn bounce(
+    path: &mut Vec<Segment>,
+    limit: usize,
+    travelled: f32,
+    (origin, dir): (Vector2<f32>, Vector2<f32>),
+    distance: f32,
+    orientation: Orientation,
+) -> Option<(Vector2<f32>, Vector2<f32>)> {
+    let bounces = path
+        .iter()
+        .filter(|segment| matches!(segment.bend, Bend::Mirror(_)))
+        .count();
+    if bounces >= limit {
+        return None;
+    }
+    if path.is_empty() {
+        path.push(Segment::new(0.0, origin, dir, Bend::Start));
+    }
+    let hit = origin + dir * distance;
+    let (dir, plane) = if orientation == Orientation::N || orientation == Orientation::S {
+        (
+            Vector2::new(dir.x, -dir.y),
+            Plane::Y(hit.y.round() as usize),
+        )
+    } else {
+        (
+            Vector2::new(-dir.x, dir.y),
+            Plane::X(hit.x.round() as usize),
+        )
+    };
+    path.push(Segment::new(
+        travelled + distance,
+        hit,
+        dir,
+        Bend::Mirror(plane),
+    ));
+    Some((hit, dir))
+}
+
+/// World directions of a wall texture's x and up and of the wall face
+#[inline(always)]
+fn wall_basis(orientation: &Orientation) -> [[f32; 3]; 3] {
+    let (along, out) = match orientation {
+        Orientation::N => ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        Orientation::S => ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        Orientation::E => ([0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]),
+        Orientation::W => ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0]),
+    };
+    [along, [0.0, 0.0, 1.0], out]
+}
+
+/// One column of the frame along with the depth of the closest surface drawn at each of its
+/// pixels. Depths are distances along the view direction, like the sprites' camera space depth
+pub struct Column<'a> {
+    pub pixels: &'a mut [u8],
+    pub depth: &'a mut [f32],
+    ray_cos: f32,
+    ray_tan: f32,
+    /// Views the ray went into at mirrors and portals, with the depths it entered and left
+    /// each of them, in the order it went
+    views: Vec<(usize, [f32; 2])>,
+}
+
+impl<'a> Column<'a> {
+    pub fn new(pixels: &'a mut [u8], depth: &'a mut [f32], ray_angle: f32) -> Self {
+        depth.fill(f32::INFINITY);
+        Self {
+            pixels,
+            depth,
+            ray_cos: ray_angle.cos(),
+            ray_tan: ray_angle.tan(),
+            views: vec![],
+        }
+    }
+
+    /// Tints what is seen in a mirror at `depth` between its `rows`, things in front of it
+    /// keep their colors
+    pub fn reflect(&mut self, rows: [f32; 2], depth: f32, tint: [f32; 3]) {
+        let end = (rows[1].max(0.0) as usize).min(self.depth.len());
+        for y in rows[0].max(0.0) as usize..end {
+            if self.depth[y] <= depth {
+                continue;
+            }
+            for (p, t) in self.pixels[y * 4..y * 4 + 3].iter_mut().zip(tint) {
+                *p = (*p as f32 * t) as u8;
+            }
+        }
+    }
+
+    /// Keeps the depths at which the ray went into each view along its `path`
+    pub fn look(&mut self, views: &Views, path: &[Segment]) {
+        self.views.clear();
+        for (k, segment) in path.iter().enumerate().skip(1) {
+            let exit = path
+                .get(k + 1)
+                .map_or(f32::INFINITY, |next| self.depth_of(next.distance));
+            if let Some(view) = views.find(&path[1..=k]) {
+                self.views
+                    .push((view, [self.depth_of(segment.distance), exit]));
+            }
+        }
+    }
+
+    /// Blends a fading upside down copy of the rows above `bottom` into the floor below it
+    /// over `rect_h` rows, `shine` is how much the floor at a row reflects
+    pub fn mirror_floor(&mut self, bottom: f32, rect_h: f32, shine: impl Fn(usize) -> f32) {
+        let start = bottom.max(0.0) as usize;
+        for y in start..self.depth.len() {
+            let offset = y as f32 - bottom;
+            let fade = 1.0 - offset / rect_h;
+            let source = bottom - offset - 1.0;
+            if fade <= 0.0 || source < 0.0 {
+                break;
+            }
+            let reflect = shine(y) * fade;
+            if reflect > 0.0 {
+                let source = source as usize * 4;
+                for c in 0..3 {
+                    let floor = self.pixels[y * 4 + c] as f32;
+                    let wall = self.pixels[source + c] as f32;
+                    self.pixels[y * 4 + c] = (floor + (wall - floor) * reflect) as u8;
+                }
+            }
+        }
+    }
+
+    /// Whether something at `depth` seen in `view`, or straight for `None`, is where the ray
+    /// went at that depth
+    #[inline(always)]
+    pub fn shows(&self, view: Option<usize>, depth: f32) -> bool {
+        match view {
+            None => self
+                .views
+                .first()
+                .is_none_or(|&(_, [entry, _])| depth < entry),
+            Some(view) => self
+                .views
+                .iter()
+                .any(|&(v, [entry, exit])| v == view && depth >= entry && depth < exit),
+        }
+    }
+
+    /// Tangents of the horizontal and vertical angles between the view direction and the
+    /// pixel at `y`
+    #[inline(always)]
+    pub fn view_offset(&self, y: usize, planedist: f32) -> [f32; 2] {
+        [
+            self.ray_tan,
+            (y as f32 - self.depth.len() as f32 * 0.5) / planedist,
+        ]
+    }
+
+    /// Depth of a point `distance` away along this column's ray
+    #[inline(always)]
+    pub fn depth_of(&self, distance: f32) -> f32 {
+        distance * self.ray_cos
+    }
+
+    /// Whether a surface at `depth` is in front of what has been drawn at `y` so far
+    #[inline(always)]
+    pub fn visible(&self, y: usize, depth: f32) -> bool {
+        self.depth.get(y).is_some_and(|&d| depth < d)
+    }
+}
+
+/// Line a mirror face lies on, `x = n` or `y = n`
+#[derive(Copy, Clone, PartialEq)]
+pub enum Plane {
+    X(usize),
+    Y(usize),
+}
+
+/// What a ray's straight part starts at
+#[derive(Copy, Clone, PartialEq)]
+pub enum Bend {
+    Start,
+    Mirror(Plane),
+    /// Map cell of the portal it came out of
+    Portal(usize),
+}
+
+/// Every way the rays of a frame bent at mirrors and portals, things seen that way are
+/// projected from where they show up
+#[derive(Default)]
+pub struct Views {
+    bends: Vec<Vec<Bend>>,
+}
+
+impl Views {
+    /// Collects the bends the rays went through up to each of their straight parts
+    pub fn collect(rays: &[(Intersection, Vec<Intersection>)]) -> Self {
+        let mut views = Self::default();
+        for (intersection, _) in rays {
+            for k in 1..intersection.path.len() {
+                if views.find(&intersection.path[1..=k]).is_none() {
+                    let bends = intersection.path[1..=k].iter().map(|s| s.bend).collect();
+                    views.bends.push(bends);
+                }
+            }
+        }
+        views
+    }
+
+    pub fn len(&self) -> usize {
+        self.bends.len()
+    }
+
+    /// View reached by the straight parts of a path after its start
+    fn find(&self, path: &[Segment]) -> Option<usize> {
+        self.bends.iter().position(|bends| {
+            bends.len() == path.len() && bends.iter().zip(path).all(|(b, s)| *b == s.bend)
+        })
+    }
+
+    /// Where something at `pos` facing `facing` shows up seen in `view`, its facing there and
+    /// whether it is seen mirrored
+    pub fn seen(
+        &self,
+        view: usize,
+        portals: &HashMap<usize, Portal>,
+        mut pos: Vector2<f32>,
+        mut facing: f32,
+    ) -> (Vector2<f32>, f32, bool) {
+        let mut mirrored = false;
+        for bend in self.bends[view].iter().rev() {
+            match *bend {
+                Bend::Start => (),
+                Bend::Mirror(Plane::X(x)) => {
+                    pos.x = 2.0 * x as f32 - pos.x;
+                    facing = std::f32::consts::PI - facing;
+                    mirrored = !mirrored;
+                }
+                Bend::Mirror(Plane::Y(y)) => {
+                    pos.y = 2.0 * y as f32 - pos.y;
+                    facing = -facing;
+                    mirrored = !mirrored;
+                }
+                Bend::Portal(cell) => {
+                    let portal = &portals[&cell];
+                    pos = portal.seen_from(pos);
+                    facing -= portal.angle();
+                }
+            }
+        }
+        (pos, facing, mirrored)
+    }
+}
+
+/// Straight part of a ray bouncing off mirrors or going through portals, starting
+/// `distance` along the ray
+pub struct Segment {
+    distance: f32,
+    origin: Vector2<f32>,
+    dir: Vector2<f32>,
+    bend: Bend,
+}
+
+impl Segment {
+    pub fn new(distance: f32, origin: Vector2<f32>, dir: Vector2<f32>, bend: Bend) -> Self {
+        Self {
+            distance,
+            origin,
+            dir,
+            bend,
+        }
+    }
 }
 
 pub struct Intersection {
@@ -662,6 +1293,8 @@ pub struct Intersection {
     wall_type: usize,
     is_transparent: bool,
     is_up: bool,
+    /// Parts of the ray between the mirrors and portals it bent at, empty for straight rays
+    path: Vec<Segment>,
 }
 
 impl Intersection {
@@ -682,20 +1315,142 @@ impl Intersection {
             wall_type,
             is_transparent,
             is_up,
+            path: vec![],
         }
     }
-}
 
-enum TWandSprites<'a> {
-    TW(Intersection),
-    Sprites(&'a Sprite),
+    /// Cell the ray came from into the wall, the door of a door frame
+    #[inline(always)]
+    fn behind(&self, map_width: usize) -> usize {
+        match self.orientation {
+            Orientation::N => self.map_checkv - map_width,
+            Orientation::E => self.map_checkv - 1,
+            Orientation::S => self.map_checkv + map_width,
+            Orientation::W => self.map_checkv + 1,
+        }
+    }
+
+    /// Texture x of the hit point, wall faces read left to right seen from the front
+    #[inline(always)]
+    fn texture_x(&self, cell_size: f32) -> f32 {
+        let (inter_x, inter_y) = (self.point[0].fract(), self.point[1].fract());
+        match self.orientation {
+            Orientation::N => cell_size - 1.0 - (inter_x * cell_size).floor(),
+            Orientation::E => inter_y * cell_size,
+            Orientation::S => inter_x * cell_size,
+            Orientation::W => cell_size - 1.0 - (inter_y * cell_size).floor(),
+        }
+    }
+
+    /// Point `distance` along a ray that bent at mirrors or portals, `None` for straight rays
+    #[inline(always)]
+    fn point_at(&self, distance: f32) -> Option<Vector2<f32>> {
+        let segment = self
+            .path
+            .iter()
+            .rev()
+            .find(|segment| segment.distance <= distance)
+            .or(self.path.first())?;
+        Some(segment.origin + segment.dir * (distance - segment.distance))
+    }
 }
 
-impl TWandSprites<'_> {
-    fn distance2(&self) -> f32 {
-        match self {
-            TWandSprites::TW(tw) => tw.distance * tw.distance,
-            TWandSprites::Sprites(sprite) => sprite.distance2,
+#[cfg(test)]
+mod tests {
+
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    use super::*;
+    #[test]
+    fn mirrors_bounce_up_to_the_limit() {
+        let mut path = vec![];
+        let ray = (Vector2::new(1.5, 1.5), Vector2::new(1.0, 0.0));
+        let (origin, dir) = bounce(&mut path, 2, 0.0, ray, 2.5, Orientation::E).unwrap();
+        assert_eq!((origin.x, origin.y, dir.x, dir.y), (4.0, 1.5, -1.0, 0.0));
+        let ray = bounce(&mut path, 2, 2.5, (origin, dir), 3.0, Orientation::W).unwrap();
+        assert!(bounce(&mut path, 2, 5.5, ray, 1.0, Orientation::E).is_none());
+        assert!(path[0].bend == Bend::Start && path.len() == 3);
+
+        // Points along the ray follow it across both bounces
+        let mut intersection =
+            Intersection::new([2.0, 1.5], 6.5, 0, Orientation::E, 14, false, false);
+        assert!(intersection.point_at(1.0).is_none());
+        intersection.path = path;
+        for (distance, x) in [(1.0, 2.5), (3.5, 3.0), (6.0, 1.5)] {
+            let point = intersection.point_at(distance).unwrap();
+            assert_eq!((point.x, point.y), (x, 1.5));
         }
+
+        // Things past each bounce show up where the straightened ray reaches them
+        let rays = [(intersection, vec![])];
+        let views = Views::collect(&rays);
+        assert_eq!(views.len(), 2);
+        let pos = Vector2::new(3.0, 1.5);
+        let (seen, facing, mirrored) = views.seen(0, &HashMap::new(), pos, 0.0);
+        assert_eq!((seen.x, seen.y, facing, mirrored), (5.0, 1.5, PI, true));
+        let (seen, _, mirrored) = views.seen(1, &HashMap::new(), pos, 0.0);
+        assert_eq!((seen.x, seen.y, mirrored), (9.0, 1.5, false));
+
+        let (mut pixels, mut depth) = (vec![0; 4], vec![0.0]);
+        let mut column = Column::new(&mut pixels, &mut depth, 0.0);
+        column.look(&views, &rays[0].0.path);
+        assert!(column.shows(None, 2.0) && !column.shows(None, 3.0));
+        assert!(column.shows(Some(0), 3.0) && !column.shows(Some(1), 3.0));
+        assert!(column.shows(Some(1), 9.0) && !column.shows(Some(0), 9.0));
+    }
+
+    #[test]
+    fn portals_show_what_is_around_their_target() {
+        let portal = Portal {
+            cell: [2, 1],
+            target: [10, 5],
+            turns: 1,
+        };
+        // Cell 42 of a map 40 wide, entered from the west and left north of the target
+        let mut intersection =
+            Intersection::new([10.5, 7.0], 3.5, 0, Orientation::N, 2, false, false);
+        intersection.path = vec![
+            Segment::new(
+                0.0,
+                Vector2::new(0.5, 1.5),
+                Vector2::new(1.0, 0.0),
+                Bend::Start,
+            ),
+            Segment::new(
+                1.5,
+                Vector2::new(10.5, 5.0),
+                Vector2::new(0.0, 1.0),
+                Bend::Portal(42),
+            ),
+        ];
+        let views = Views::collect(&[(intersection, vec![])]);
+        let portals = HashMap::from([(42, portal)]);
+        let (seen, facing, mirrored) = views.seen(0, &portals, Vector2::new(10.5, 6.5), 0.0);
+        assert_eq!((seen.x, seen.y, mirrored), (3.5, 1.5, false));
+        assert_eq!(facing, -FRAC_PI_2);
+    }
+
+    #[test]
+    fn mirrors_tint_what_is_behind_them() {
+        let (mut pixels, mut depth) = (vec![200; 4 * 4], vec![0.0; 4]);
+        let mut column = Column::new(&mut pixels, &mut depth, 0.0);
+        column.depth.copy_from_slice(&[1.0, 5.0, 5.0, 1.0]);
+        column.reflect([0.0, 4.0], 3.0, [0.5; 3]);
+        assert_eq!(column.pixels[0], 200);
+        assert_eq!(column.pixels[4..8], [100, 100, 100, 200]);
+    }
+
+    #[test]
+    fn polished_floors_reflect_the_wall() {
+        let mut pixels = [[200; 16], [0; 16]].concat();
+        let mut depth = vec![0.0; 8];
+        let mut column = Column::new(&mut pixels, &mut depth, 0.0);
+        column.mirror_floor(4.0, 4.0, |_| 0.0);
+        assert!(column.pixels[16..].iter().all(|&p| p == 0));
+        column.mirror_floor(4.0, 4.0, |_| 1.0);
+        // The reflection fades out away from the bottom of the wall
+        assert_eq!(column.pixels[16..19], [200; 3]);
+        assert_eq!(column.pixels[20..23], [150; 3]);
+        assert_eq!(column.pixels[28..31], [50; 3]);
     }
 }
@@ -5,50 +5,67 @@ use ggez::{
     Context, GameResult,
 };
 
+use crate::{
+    fog::Fog,
+    texture::{Atlas, Sample},
+};
+
+/// How a surface lies in the world and where its light comes from, to shade normal maps
+#[derive(Copy, Clone)]
+pub struct Relief {
+    /// World directions of the texture's x, of its up and of the surface normal
+    pub basis: [[f32; 3]; 3],
+    /// Mean direction towards the lights reaching the surface
+    pub light: [f32; 3],
+}
+
+/// Texture sampling settings that can be toggled at runtime
+pub struct Quality {
+    pub mipmaps: bool,
+    pub bilinear: bool,
+}
+
 #[allow(dead_code)]
 pub struct Screen {
     pub img_arr: Vec<u8>,
     img_arr_len: usize,
     width: usize,
     height: usize,
-    wall_textures: Vec<f32>,
-    sprite_textures: Vec<u8>,
-    length_textures: usize,
-    length_sprites: usize,
-    shade_col: __m128,
+    pub atlas: Atlas,
+    pub quality: Quality,
+    sky_texture: Vec<u8>,
+    pub sky_size: (usize, usize),
     flashlight_col: __m128,
 }
 
 impl Screen {
-    pub unsafe fn new(
-        widthf: f32,
-        heightf: f32,
-        length_textures: usize,
-        length_sprites: usize,
-    ) -> Self {
+    pub unsafe fn new(widthf: f32, heightf: f32, atlas: Atlas) -> Self {
         let width = widthf as usize;
         let height = heightf as usize;
         let img_arr = vec![0; (width * height) * 4];
         let img_arr_len = img_arr.len();
-        let shade_v = [1.5, 1.1, 0.6, 0.0];
-        let flashlight_v = [1.0, 0.9, 0.8, 0.0];
         Self {
             img_arr,
             img_arr_len,
             width,
             height,
-            wall_textures: Vec::new(),
-            sprite_textures: Vec::new(),
-            length_textures,
-            length_sprites,
-            shade_col: _mm_loadu_ps(shade_v.as_ptr()),
-            flashlight_col: _mm_loadu_ps(flashlight_v.as_ptr()),
+            atlas,
+            quality: Quality {
+                mipmaps: true,
+                bilinear: false,
+            },
+            sky_texture: Vec::new(),
+            sky_size: (0, 0),
+            flashlight_col: _mm_setzero_ps(),
         }
     }
 
-    pub fn textures(&mut self, wall_textures: Vec<u8>, sprite_textures: Vec<u8>) {
-        self.wall_textures = wall_textures.iter().map(|&p| p as f32).collect();
-        self.sprite_textures = sprite_textures;
+    pub fn sky(&mut self, sky_texture: Vec<u8>, sky_size: (usize, usize)) {
+        self.sky_texture = sky_texture;
+        self.sky_size = sky_size;
+    }
+    pub fn flashlight(&mut self, color: [f32; 3]) {
+        self.flashlight_col = unsafe { _mm_set_ps(0.0, color[2], color[1], color[0]) };
     }
     #[allow(dead_code)]
     pub fn reset_img(&mut self) {
@@ -58,94 +75,206 @@ impl Screen {
         Image::from_rgba8(ctx, self.width as u16, self.height as u16, &self.img_arr)
     }
 
-    pub fn draw_texture(
+    /// Mip level for a pixel covering `footprint` texture units, 0 when mipmapping is off
+    #[inline(always)]
+    pub fn level(&self, texture: usize, footprint: f32) -> usize {
+        if self.quality.mipmaps {
+            self.atlas.level(texture, footprint)
+        } else {
+            0
+        }
+    }
+
+    /// Lights a texel and fades it into the fog of its cell by its `depth`
+    #[inline(always)]
+    fn shade(
         &self,
-        slice: &mut [u8],
-        texture_position: [usize; 2],
-        pixel_height: usize,
-        shade: f32,
+        texel: &[f32],
+        light: [f32; 4],
+        diffuse: f32,
         flashlight: f32,
-    ) {
-        let pos = (texture_position[1] * self.length_textures + texture_position[0]) * 4; //position of current pixel
-        let p_int = unsafe {
+        depth: f32,
+        fog: Option<&Fog>,
+    ) -> [u8; 4] {
+        let (fog, amount) = match fog {
+            Some(fog) => (fog.color, fog.amount(depth)),
+            None => ([0.0; 4], 0.0),
+        };
+        unsafe {
             color_pixel(
-                &self.wall_textures[pos..pos + 4],
-                shade,
+                texel,
+                light,
+                diffuse,
                 flashlight,
-                self.shade_col,
                 self.flashlight_col,
+                fog,
+                amount,
             )
+        }
+    }
+
+    /// How much more or less light a texel gets than a flat one because of the texture's
+    /// normal map, 1 without one
+    #[inline(always)]
+    fn diffuse(&self, sample: &Sample, relief: Option<Relief>) -> f32 {
+        let (Some(relief), Some(normal)) = (relief, self.atlas.get(sample.texture).normal) else {
+            return 1.0;
         };
+        let sample = Sample::new(normal, 0, sample.level, sample.position);
+        let texel = self.atlas.fetch(&sample, self.quality.bilinear);
+        // Tilt of the texel away from the flat normal, which lies along the third axis
+        let tilt = [
+            texel[0] / 127.5 - 1.0,
+            texel[1] / 127.5 - 1.0,
+            texel[2] / 127.5 - 2.0,
+        ];
+        let bend: f32 = relief
+            .basis
+            .iter()
+            .zip(tilt)
+            .map(|(axis, t)| {
+                t * (axis[0] * relief.light[0]
+                    + axis[1] * relief.light[1]
+                    + axis[2] * relief.light[2])
+            })
+            .sum();
+        (1.0 + bend).clamp(0.0, 2.0)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_texture(
+        &self,
+        slice: &mut [u8],
+        sample: Sample,
+        pixel_height: usize,
+        light: [f32; 4],
+        flashlight: f32,
+        depth: f32,
+        fog: Option<&Fog>,
+        relief: Option<Relief>,
+    ) {
+        let texel = self.atlas.fetch(&sample, self.quality.bilinear);
+        let diffuse = self.diffuse(&sample, relief);
+        let p_int = self.shade(&texel, light, diffuse, flashlight, depth, fog);
         slice[(pixel_height * 4)..(pixel_height * 4) + 4].copy_from_slice(&p_int);
     }
+    /// Returns whether the texel was opaque and got drawn
+    #[allow(clippy::too_many_arguments)]
     pub fn draw_transparent_texture(
         &self,
         slice: &mut [u8],
-        texture_position: [usize; 2],
+        sample: Sample,
         pixel_height: usize,
-        shade: f32,
+        light: [f32; 4],
         flashlight: f32,
-    ) {
-        let pos = (texture_position[1] * self.length_textures + texture_position[0]) * 4; //position of current pixel
-        if self.wall_textures[pos + 3] == 255.0 {
-            let p = unsafe {
-                color_pixel(
-                    &self.wall_textures[pos..pos + 4],
-                    shade,
-                    flashlight,
-                    self.shade_col,
-                    self.flashlight_col,
-                )
-                //[p[0] as u8, p[1] as u8, p[2] as u8, 255]
-            };
+        depth: f32,
+        fog: Option<&Fog>,
+        relief: Option<Relief>,
+    ) -> bool {
+        let texel = self.atlas.fetch(&sample, self.quality.bilinear);
+        if texel[3] >= 128.0 {
+            let diffuse = self.diffuse(&sample, relief);
+            let p = self.shade(&texel, light, diffuse, flashlight, depth, fog);
 
             slice[(pixel_height * 4)..(pixel_height * 4) + 4].copy_from_slice(&p);
+            true
+        } else {
+            false
         }
     }
+    /// `light` holds the colored light and flashlight terms, fullbright sprites have none and keep
+    /// their texture colors apart from the fog. Returns whether the texel was opaque and got drawn
     pub fn draw_sprite(
         &self,
         slice: &mut [u8],
-        texture_position: [usize; 2],
+        sample: Sample,
         pixel_height: usize,
-        shade: f32,
-    ) {
-        let pos = (texture_position[1] * self.length_sprites + texture_position[0]) << 2; //position of current pixel
-                                                                                          // draws in rectangles of 1xwidth_rect size
-        let mut pixel: [u8; 4] = self.sprite_textures[pos..pos + 4].try_into().unwrap(); //rgba pixel
-                                                                                         //Doesn't draw transparent pixels
-        if pixel[3] == 255 {
-            if shade != 1.0 && pixel != [255, 0, 0, 255] {
-                //Draws shade depening of current lighting, darkening or brightening the pixel
-                (0..3).for_each(|j| pixel[j] = (pixel[j] as f32 * shade) as u8);
-            }
+        light: Option<([f32; 4], f32)>,
+        depth: f32,
+        fog: Option<&Fog>,
+    ) -> bool {
+        let texel = self.atlas.fetch(&sample, self.quality.bilinear);
+        //Doesn't draw transparent pixels
+        if texel[3] >= 128.0 {
+            let red = texel.map(|p| p as u8)[..3] == [255, 0, 0];
+            //Draws shade depening of current lighting, darkening or brightening the pixel
+            let pixel = match light {
+                Some((light, flashlight)) if !red => {
+                    self.shade(&texel, light, 1.0, flashlight, depth, fog)
+                }
+                _ => self.shade(&texel, [1.0; 4], 1.0, 0.0, depth, fog),
+            };
 
             slice[(pixel_height << 2)..(pixel_height << 2) + 4].copy_from_slice(&pixel);
+            true
+        } else {
+            false
         }
     }
+    /// Blends a decal texel over the surface drawn at `pixel_height` by its alpha, lit the
+    /// same way as the surface
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_decal(
+        &self,
+        slice: &mut [u8],
+        sample: Sample,
+        pixel_height: usize,
+        light: [f32; 4],
+        flashlight: f32,
+        depth: f32,
+        fog: Option<&Fog>,
+    ) {
+        let texel = self.atlas.fetch(&sample, self.quality.bilinear);
+        let alpha = texel[3] / 255.0;
+        if alpha > 0.0 {
+            let p = self.shade(&texel, light, 1.0, flashlight, depth, fog);
+            let pixel = &mut slice[(pixel_height << 2)..(pixel_height << 2) + 3];
+            for (c, d) in pixel.iter_mut().zip(p) {
+                *c = (*c as f32 + (d as f32 - *c as f32) * alpha) as u8;
+            }
+        }
+    }
+    pub fn draw_sky(&self, slice: &mut [u8], texture_position: [usize; 2], pixel_height: usize) {
+        let pos = (texture_position[1] * self.sky_size.0 + texture_position[0]) << 2; //position of current pixel
+        slice[(pixel_height << 2)..(pixel_height << 2) + 4]
+            .copy_from_slice(&self.sky_texture[pos..pos + 4]);
+    }
     /*
     pub fn draw_pixel(&self, img_arr: &mut [u8], pos: usize, pixel: &[u8; 4]) {
         img_arr[(pos << 2)..(pos << 2) + 4].copy_from_slice(pixel);
     }*/
 }
 
+/// Scales a texel by the rgb `light` reaching it, bent by the `diffuse` term of its normal,
+/// plus the flashlight, then mixes `fog` in
+#[allow(clippy::too_many_arguments)]
 #[inline(always)]
 unsafe fn color_pixel(
     pixel: &[f32],
-    shade: f32,
+    light: [f32; 4],
+    diffuse: f32,
     flashlight: f32,
-    v_shade_col: __m128,
     v_flashlight_col: __m128,
+    fog: [f32; 4],
+    fog_amount: f32,
 ) -> [u8; 4] {
     let v_pixel = _mm_loadu_ps(pixel.as_ptr());
-    let v_shade = _mm_set1_ps(shade);
+    let v_light = _mm_loadu_ps(light.as_ptr());
     let v_flashlight = _mm_set1_ps(flashlight);
     let v_twofivefive = _mm_set1_epi32(255);
     let twofivefive = _mm_set_epi32(255, 0, 0, 0);
     let multiplicator = _mm_add_ps(
-        _mm_mul_ps(v_shade_col, v_shade),
+        _mm_mul_ps(v_light, _mm_set1_ps(diffuse)),
         _mm_mul_ps(v_flashlight, v_flashlight_col),
     );
     let out = _mm_mul_ps(v_pixel, multiplicator);
+    let out = _mm_add_ps(
+        out,
+        _mm_mul_ps(
+            _mm_sub_ps(_mm_loadu_ps(fog.as_ptr()), out),
+            _mm_set1_ps(fog_amount),
+        ),
+    );
     let out = _mm_add_epi32(_mm_cvtps_epi32(out), twofivefive);
     let p = _mm_min_epi32(out, v_twofivefive);
     let p: __m128i = core::mem::transmute(p);
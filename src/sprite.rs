@@ -1,68 +1,267 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
 use ggez::{
     graphics::{self},
-    Context,
+    Context, GameResult,
+};
+
+use crate::{
+    fog::Atmosphere,
+    lighting::Lighting,
+    player::Player,
+    portal::Portal,
+    render::{Column, Views},
+    screen::Screen,
+    texture::{Atlas, Sample, UNITS},
+    utilities::{datafile, math::circle_overlaps_cell, vector2::Vector2},
 };
 
-use crate::{player::Player, screen::Screen, utilities::vector2::Vector2};
-const PI: f32 = std::f32::consts::PI;
-const TEX_SIZE: usize = 128;
+/// Where a sprite stands on the storey it is placed on
+#[derive(Copy, Clone, PartialEq)]
+pub enum Anchor {
+    Floor,
+    Ceiling,
+    Wall,
+}
+
+impl FromStr for Anchor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "floor" => Anchor::Floor,
+            "ceiling" => Anchor::Ceiling,
+            "wall" => Anchor::Wall,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Named run of animation frames of a sprite sheet
+#[derive(Clone)]
+pub struct Clip {
+    pub name: String,
+    first: usize,
+    frames: usize,
+    fps: f32,
+}
+
+/// What a kind of prop looks like, sizes are in world units where a wall is 1x1
+#[derive(Clone)]
+pub struct Definition {
+    pub texture: usize,
+    pub width: f32,
+    pub height: f32,
+    pub anchor: Anchor,
+    pub clips: Vec<Clip>,
+    pub fullbright: bool,
+    /// Solid sprites block movement within this distance of their position
+    pub radius: f32,
+}
+
+impl Definition {
+    pub fn clip(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|clip| clip.name == name)
+    }
+
+    /// Height of the sprite's center above the floor of its storey
+    fn center(&self) -> f32 {
+        match self.anchor {
+            Anchor::Floor => self.height * 0.5,
+            Anchor::Ceiling => 1.0 - self.height * 0.5,
+            Anchor::Wall => 0.5,
+        }
+    }
+}
+
+/// `[sprites]` lines are `name texture width height anchor [radius=n] [fullbright=bool]`,
+/// `[clips]` lines are `sprite clip first frames fps` counted in animation frames of the
+/// sheet, so each of them holds every rotation of the sprite
+pub fn load_definitions(
+    ctx: &mut Context,
+    path: &Path,
+    atlas: &Atlas,
+) -> GameResult<HashMap<String, Definition>> {
+    let mut definitions = HashMap::new();
+    for entry in datafile::read(ctx, path)? {
+        match entry.section.as_str() {
+            "sprites" => {
+                let anchor = entry.name(4)?;
+                definitions.insert(
+                    entry.name(0)?.to_string(),
+                    Definition {
+                        texture: atlas.id(entry.name(1)?)?,
+                        width: entry.field(2)?,
+                        height: entry.field(3)?,
+                        anchor: anchor
+                            .parse()
+                            .map_err(|_| entry.error(&format!("unknown anchor '{}'", anchor)))?,
+                        clips: vec![],
+                        fullbright: entry.option("fullbright")?.unwrap_or(false),
+                        radius: entry.option("radius")?.unwrap_or(0.0),
+                    },
+                );
+            }
+            "clips" => {
+                let name = entry.name(0)?;
+                let definition: &mut Definition = definitions
+                    .get_mut(name)
+                    .ok_or_else(|| entry.error(&format!("unknown sprite '{}'", name)))?;
+                let texture = atlas.get(definition.texture);
+                let clip = Clip {
+                    name: entry.name(1)?.to_string(),
+                    first: entry.field(2)?,
+                    frames: entry.field(3)?,
+                    fps: entry.field(4)?,
+                };
+                if clip.frames == 0 || clip.first + clip.frames > texture.frames / texture.rotations
+                {
+                    return Err(entry.error(&format!("'{}' is outside of the sheet", clip.name)));
+                }
+                definition.clips.push(clip);
+            }
+            section => return Err(entry.error(&format!("unknown section '{}'", section))),
+        }
+    }
+    Ok(definitions)
+}
+
 pub struct Sprite {
-    pub stype: usize,
+    pub definition: Definition,
     pub pos: Vector2<f32>,
-    pub height: f32,
-    pub visible: bool,
+    /// World angle the front of the sprite faces, in radians
+    pub facing: f32,
+    /// 0 for the ground floor, 1 for the one above
+    pub storey: f32,
+    pub clip: Option<usize>,
     time: f32,
-    pub bounds: Bounds,
-    pub distance2: f32,
-    shade: f32,
+    /// Everywhere the sprite is on screen, straight ahead and in mirrors and portals
+    views: Vec<View>,
+    light: Option<[f32; 4]>,
+    /// Map cell the sprite stands in, on its storey
+    cell: usize,
+}
+
+/// Where a sprite shows up on screen, seen straight or in mirrors and portals
+struct View {
+    /// View of the mirrors and portals it is seen in
+    view: Option<usize>,
+    /// Position and facing as seen from the player
+    pos: Vector2<f32>,
+    facing: f32,
+    /// Seen in an odd number of mirrors, so flipped left to right
+    mirrored: bool,
+    bounds: Bounds,
+    distance: f32,
+    depth: f32,
 }
 
 impl Sprite {
-    pub fn new(stype: SpriteType, pos: Vector2<f32>, height: f32) -> Self {
+    pub fn new(
+        definition: Definition,
+        pos: Vector2<f32>,
+        facing: f32,
+        storey: f32,
+        clip: Option<usize>,
+    ) -> Self {
         Self {
-            stype: stype as usize,
+            definition,
             pos,
-            height,
-            visible: false,
+            facing,
+            storey,
+            clip,
             time: 0.0,
-            bounds: Bounds::default(),
-            distance2: 0.0,
-            shade: 0.0,
+            views: vec![],
+            light: None,
+            cell: 0,
         }
     }
-    pub fn calculate_distance_2(&self, player: &Player) -> f32 {
-        (player.pos.x - self.pos.x) * (player.pos.x - self.pos.x)
-            + (player.pos.y - self.pos.y) * (player.pos.y - self.pos.y) //square root not necessary
+    pub fn solid(&self) -> bool {
+        self.definition.radius > 0.0
+    }
+
+    /// Whether a circle of `radius` moving from `from` to `to` runs into the sprite. Moving
+    /// away is always allowed so nothing gets stuck inside a sprite
+    pub fn blocks(&self, from: Vector2<f32>, to: Vector2<f32>, radius: f32) -> bool {
+        let distance = (to - self.pos).magnitude();
+        self.solid()
+            && distance < self.definition.radius + radius
+            && distance < (from - self.pos).magnitude()
+    }
+
+    pub fn overlaps_cell(&self, cell: [usize; 2]) -> bool {
+        self.solid() && circle_overlaps_cell(self.pos, self.definition.radius, cell)
     }
 
     pub fn update(&mut self, time: f32) {
         self.time = time;
     }
 
-    pub fn set_drawing_bounds(&mut self, ctx: &mut Context, player: &Player, rays_per_pixel: f32) {
+    /// First frame of the current animation frame, the rotations follow it in the sheet
+    fn animation_frame(&self, atlas: &Atlas) -> usize {
+        let frame = match self.clip {
+            Some(clip) => {
+                let clip = &self.definition.clips[clip];
+                clip.first + (self.time * clip.fps) as usize % clip.frames
+            }
+            None => 0,
+        };
+        frame * atlas.get(self.definition.texture).rotations
+    }
+
+    /// Projects the sprite straight ahead and where it shows up in each of the `views` the
+    /// rays went into, keeping the ones that are on screen
+    pub fn set_drawing_bounds(
+        &mut self,
+        ctx: &mut Context,
+        player: &Player,
+        rays_per_pixel: f32,
+        views: &Views,
+        portals: &HashMap<usize, Portal>,
+    ) {
         let (w, h) = graphics::drawable_size(ctx);
-        let sprite_delpos = self.pos - player.pos;
+        self.views.clear();
+        let straight = self.view(player, rays_per_pixel, (w, h), self.pos, self.facing);
+        self.views.extend(straight);
+        for i in 0..views.len() {
+            let (pos, facing, mirrored) = views.seen(i, portals, self.pos, self.facing);
+            let view = self.view(player, rays_per_pixel, (w, h), pos, facing);
+            self.views.extend(view.map(|view| View {
+                view: Some(i),
+                mirrored,
+                ..view
+            }));
+        }
+    }
+
+    fn view(
+        &self,
+        player: &Player,
+        rays_per_pixel: f32,
+        (w, h): (f32, f32),
+        pos: Vector2<f32>,
+        facing: f32,
+    ) -> Option<View> {
+        let sprite_delpos = pos - player.pos;
         let inv_det =
             1.0 / (player.plane.x * player.dir_norm.y - player.dir_norm.x * player.plane.y);
-        self.distance2 = self.calculate_distance_2(player);
         let transform_x =
             inv_det * (player.dir_norm.y * sprite_delpos.x - player.dir_norm.x * sprite_delpos.y);
         let transform_y =
             inv_det * (-player.plane.y * sprite_delpos.x + player.plane.x * sprite_delpos.y);
         let sprite_screen_x = (w / rays_per_pixel * 0.5) * (1.0 + transform_x / transform_y);
-        let sprite_size = (player.planedist / transform_y).abs() / rays_per_pixel;
-        let sprite_size_y = sprite_size * rays_per_pixel;
-        let mut start_y = -sprite_size_y * 0.5
-            + h * 0.5
-            + player.pitch
-            + (player.jump + self.height) / transform_y;
+        let scale = (player.planedist / transform_y).abs();
+        let sprite_size = scale * self.definition.width / rays_per_pixel;
+        let sprite_size_y = scale * self.definition.height;
+        // Screen offset of the sprite's center from the middle of a wall of its storey
+        let height = (0.5 - self.storey - self.definition.center()) * player.planedist;
+        let mut start_y =
+            -sprite_size_y * 0.5 + h * 0.5 + player.pitch + (player.jump + height) / transform_y;
         if start_y < 0.0 {
             start_y = 0.0;
         }
-        let mut end_y = sprite_size_y * 0.5
-            + h * 0.5
-            + player.pitch
-            + (player.jump + self.height) / transform_y;
+        let mut end_y =
+            sprite_size_y * 0.5 + h * 0.5 + player.pitch + (player.jump + height) / transform_y;
         if end_y > h - 1.0 {
             end_y = h - 1.0;
         }
@@ -76,91 +275,102 @@ impl Sprite {
             end_x = w - 1.0;
         }
 
-        let mut sty = Vec::new();
-
-        if transform_y > 0.0 && start_x < w && end_x > 0.0 && end_y > 0.0 && start_y < h {
-            self.visible = true;
+        if !(transform_y > 0.0 && start_x < w && end_x > 0.0 && end_y > 0.0 && start_y < h) {
+            return None;
+        }
+        let denominator = UNITS as f32 / sprite_size_y;
+        let sty = (start_y as usize..1 + end_y as usize)
+            .map(|y| {
+                //for every pixel of the current stripe
+                let d = (y as f32) - h * 0.5 + sprite_size_y * 0.5
+                    - player.pitch
+                    - (player.jump + height) / transform_y;
 
-            let denominator = TEX_SIZE as f32 / sprite_size_y;
-            sty = (start_y as usize..1 + end_y as usize)
-                .map(|y| {
-                    //for every pixel of the current stripe
-                    let d = (y as f32) - h * 0.5 + sprite_size_y * 0.5
-                        - player.pitch
-                        - (player.jump + self.height) / transform_y;
+                d * denominator
+            })
+            .collect();
 
-                    (d * denominator) as usize
-                })
-                .collect();
+        Some(View {
+            view: None,
+            pos,
+            facing,
+            mirrored: false,
+            bounds: Bounds::new(
+                start_y,
+                end_y,
+                start_x,
+                end_x,
+                sprite_screen_x,
+                sty,
+                sprite_size,
+            ),
+            distance: sprite_delpos.magnitude(),
+            depth: transform_y,
+        })
+    }
 
-            self.shade = {
-                if self.stype == SpriteType::Torch as usize {
-                    1.0
-                } else {
-                    num::clamp(5.0 / self.distance2, 0.2, 1.0)
-                }
-            };
+    /// Lights the sprite like the floor under it
+    pub fn set_lighting(&mut self, lighting: &Lighting, map_size: (usize, usize)) {
+        let layer = (self.storey as usize).min(1) * map_size.0 * map_size.1;
+        let cell = self.pos.x as usize + self.pos.y as usize * map_size.0 + layer;
+        self.cell = cell;
+        self.light = if self.definition.fullbright {
+            None
         } else {
-            self.visible = false;
-        }
-
-        self.bounds = Bounds::new(
-            start_y,
-            end_y,
-            start_x,
-            end_x,
-            sprite_screen_x,
-            sty,
-            sprite_size,
-        );
+            let shade = lighting.get_lighting_floor(
+                self.pos.x.fract() * UNITS as f32,
+                self.pos.y.fract() * UNITS as f32,
+                cell,
+            );
+            Some(shade)
+        };
     }
 
+    /// Draws the sprite's views on a column, fogged by the cell the sprite stands in
     pub fn draw(
         &self,
-        slice: &mut [u8],
+        column: &mut Column,
         player: &Player,
         j: usize,
         screen: &Screen,
-        distance: f32,
+        atmosphere: &Atmosphere,
     ) {
         let stripe = j as f32;
-
-        if self.visible && stripe >= self.bounds.start_x && stripe < self.bounds.end_x {
-            let sprite_delpos = self.pos - player.pos;
-            let mut angle = (sprite_delpos.y).atan2(sprite_delpos.x);
-            let angle_player = player.dir_norm.angle();
-            let delta_angle = angle - angle_player;
-            let cos = delta_angle.cos();
-            let mut sprite_rotation = 0;
-            if self.stype == SpriteType::Bat as usize {
-                if angle < 0.0 {
-                    angle += 2.0 * PI;
-                }
-                sprite_rotation = (angle / (2.0 * PI) * 8.0).round() as usize;
-
-                sprite_rotation -= 7;
-            } else if self.stype == SpriteType::Torch as usize {
-                sprite_rotation = (self.time * 1.1 % 1.0 * 8.0) as usize;
+        let fog = atmosphere.at(self.cell);
+        for view in self.views.iter() {
+            let bounds = &view.bounds;
+            if stripe < bounds.start_x || stripe >= bounds.end_x {
+                continue;
             }
-            if sprite_rotation > 7 {
-                sprite_rotation = 0;
+            // Sheets hold every rotation of each animation frame next to each other, mirrors
+            // show the side turned the other way flipped
+            let mut view_angle = (player.pos - view.pos).angle() - view.facing;
+            let texture = self.definition.texture;
+            let mut stx = (stripe - (-bounds.size * 0.5 + bounds.sprite_screen_x)) * UNITS as f32
+                / bounds.size;
+            if view.mirrored {
+                view_angle = -view_angle;
+                stx = UNITS as f32 - 1.0 - stx;
             }
-
-            let stx = ((stripe - (-self.bounds.size as f32 * 0.5 + self.bounds.sprite_screen_x))
-                * TEX_SIZE as f32
-                / self.bounds.size) as usize;
-            if (distance * distance) / (cos * cos) > self.distance2 {
-                for y in self.bounds.start_y as usize..1 + self.bounds.end_y as usize {
-                    screen.draw_sprite(
-                        slice,
-                        [
-                            sprite_rotation * TEX_SIZE + stx,
-                            self.stype * TEX_SIZE
-                                + self.bounds.sty[y - self.bounds.start_y as usize],
-                        ],
-                        y,
-                        self.shade,
-                    );
+            let frame =
+                self.animation_frame(&screen.atlas) + screen.atlas.rotation(texture, view_angle);
+            let level = screen.level(texture, UNITS as f32 / bounds.size);
+            for y in bounds.start_y as usize..1 + bounds.end_y as usize {
+                if !column.visible(y, view.depth) || !column.shows(view.view, view.depth) {
+                    continue;
+                }
+                let light = self.light.map(|light| {
+                    let offset = column.view_offset(y, player.planedist);
+                    (light, player.flashlight.light(view.distance, offset))
+                });
+                let sample = Sample::new(
+                    texture,
+                    frame,
+                    level,
+                    [stx, bounds.sty[y - bounds.start_y as usize]],
+                );
+                if screen.draw_sprite(column.pixels, sample, y, light, view.depth, fog) {
+                    column.depth[y] = view.depth;
                 }
             }
         }
@@ -173,7 +383,7 @@ pub struct Bounds {
     start_x: f32,
     end_x: f32,
     sprite_screen_x: f32,
-    sty: Vec<usize>,
+    sty: Vec<f32>,
     size: f32,
 }
 
@@ -184,7 +394,7 @@ impl Bounds {
         start_x: f32,
         end_x: f32,
         sprite_screen_x: f32,
-        sty: Vec<usize>,
+        sty: Vec<f32>,
         size: f32,
     ) -> Self {
         Self {
@@ -198,17 +408,3 @@ impl Bounds {
         }
     }
 }
-
-impl Default for Bounds {
-    fn default() -> Self {
-        Self::new(0.0, 0.0, 0.0, 0.0, 0.0, vec![], 0.0)
-    }
-}
-#[allow(dead_code)]
-pub enum SpriteType {
-    Armor = 0,
-    CandleHolder = 1,
-    Bat = 2,
-    Torch = 3,
-    Gore = 4,
-}
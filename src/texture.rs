@@ -0,0 +1,378 @@
+use std::{collections::HashMap, path::Path};
+
+use ggez::{graphics, Context, GameError, GameResult};
+
+use crate::utilities::datafile;
+
+/// Texture coordinates are given in 1/128ths of a texture whatever its size
+pub const UNITS: usize = 128;
+
+const MAX_LEVELS: usize = 16;
+
+#[derive(Copy, Clone)]
+pub struct Texture {
+    offset: usize,
+    pub size: usize,
+    pub frames: usize,
+    pub fps: f32,
+    pub rotations: usize,
+    /// Texture holding the tangent space normals of this one
+    pub normal: Option<usize>,
+    /// How much polished floors reflect the walls, from 0 to 1
+    pub reflect: f32,
+    levels: usize,
+    level_offsets: [usize; MAX_LEVELS],
+    chain_len: usize,
+}
+
+/// Where to read a texture from, `position` is given in `UNITS`
+#[derive(Copy, Clone)]
+pub struct Sample {
+    pub texture: usize,
+    pub frame: usize,
+    pub level: usize,
+    pub position: [f32; 2],
+}
+
+impl Sample {
+    pub fn new(texture: usize, frame: usize, level: usize, position: [f32; 2]) -> Self {
+        Self {
+            texture,
+            frame,
+            level,
+            position,
+        }
+    }
+}
+
+/// Every texture of the manifest packed one after another as f32 rgba. Each frame is
+/// stored with its whole mip chain, halving the size down to 1x1
+#[derive(Default)]
+pub struct Atlas {
+    pub data: Vec<f32>,
+    textures: Vec<Texture>,
+    names: HashMap<String, usize>,
+}
+
+impl Atlas {
+    /// Each manifest line is `name file x y size [frames=n] [fps=n] [rotations=n]`, the frames of a
+    /// texture are square regions of a power of two size laid out left to right in the image
+    /// starting at (x, y). Textures with a frame rate are animated. Sprite sheets can hold 1, 8 or
+    /// 16 rotations per animation frame, stored next to each other starting with the front view. A
+    /// normal map can be any other texture of the manifest and floors with `reflect` mirror the
+    /// walls faintly
+    pub fn load(ctx: &mut Context, path: &Path) -> GameResult<Self> {
+        let mut atlas = Atlas::default();
+        let mut images: HashMap<String, (Vec<u8>, usize)> = HashMap::new();
+        let mut normals = vec![];
+        let entries = datafile::read(ctx, path)?;
+        for entry in &entries {
+            let name = entry.name(0)?;
+            let file = entry.name(1)?;
+            let corner = [entry.field(2)?, entry.field(3)?];
+            let size: usize = entry.field(4)?;
+            let frames = entry.option("frames")?.unwrap_or(1);
+            let fps = entry.option("fps")?.unwrap_or(0.0);
+            let rotations = entry.option("rotations")?.unwrap_or(1);
+            if ![1, 8, 16].contains(&rotations) || frames % rotations != 0 {
+                return Err(entry.error(&format!(
+                    "'{}' can't have {} rotations with {} frames",
+                    name, rotations, frames
+                )));
+            }
+
+            if !images.contains_key(file) {
+                let image = graphics::Image::new(ctx, file)?;
+                let width = image.width() as usize;
+                images.insert(file.to_string(), (image.to_rgba8(ctx)?, width));
+            }
+            let (pixels, width) = &images[file];
+            let image = [*width, pixels.len() / (width * 4)];
+            if let Err(e) = check_region(corner, size, frames, image) {
+                return Err(entry.error(&format!("'{}' {} of {}", name, e, file)));
+            }
+            let id = atlas.add(name, pixels, *width, corner, size, frames, fps, rotations);
+            atlas.textures[id].reflect = entry.option("reflect")?.unwrap_or(0.0);
+            if let Some(normal) = entry.option::<String>("normal")? {
+                normals.push((id, normal, entry));
+            }
+        }
+        for (id, normal, entry) in normals {
+            let normal = atlas.id(&normal).map_err(|e| entry.error(&e.to_string()))?;
+            atlas.textures[id].normal = Some(normal);
+        }
+        Ok(atlas)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add(
+        &mut self,
+        name: &str,
+        pixels: &[u8],
+        width: usize,
+        corner: [usize; 2],
+        size: usize,
+        frames: usize,
+        fps: f32,
+        rotations: usize,
+    ) -> usize {
+        let offset = self.data.len();
+        let mut levels = 0;
+        let mut level_offsets = [0; MAX_LEVELS];
+        let mut chain_len = 0;
+        let mut level_size = size;
+        while level_size > 0 && levels < MAX_LEVELS {
+            level_offsets[levels] = chain_len;
+            chain_len += level_size * level_size * 4;
+            levels += 1;
+            level_size /= 2;
+        }
+        for frame in 0..frames {
+            let mut level: Vec<f32> = (corner[1]..corner[1] + size)
+                .flat_map(|row| {
+                    let start = (row * width + corner[0] + frame * size) * 4;
+                    pixels[start..start + size * 4].iter().map(|&p| p as f32)
+                })
+                .collect();
+            self.data.extend_from_slice(&level);
+            for l in 1..levels {
+                level = downsample(&level, size >> (l - 1));
+                self.data.extend_from_slice(&level);
+            }
+        }
+        self.textures.push(Texture {
+            offset,
+            size,
+            frames,
+            fps,
+            rotations,
+            normal: None,
+            reflect: 0.0,
+            levels,
+            level_offsets,
+            chain_len,
+        });
+        self.names.insert(name.to_string(), self.textures.len() - 1);
+        self.textures.len() - 1
+    }
+
+    pub fn id(&self, name: &str) -> GameResult<usize> {
+        self.names
+            .get(name)
+            .copied()
+            .ok_or_else(|| GameError::ResourceLoadError(format!("unknown texture '{}'", name)))
+    }
+
+    pub fn get(&self, id: usize) -> &Texture {
+        &self.textures[id]
+    }
+
+    /// Current frame of an animated texture, the first rotation of it for sprite sheets
+    #[inline(always)]
+    pub fn frame(&self, id: usize, time: f32) -> usize {
+        let texture = &self.textures[id];
+        if texture.fps > 0.0 {
+            (time * texture.fps) as usize % (texture.frames / texture.rotations) * texture.rotations
+        } else {
+            0
+        }
+    }
+
+    /// Rotation of a sprite seen from `angle` radians counterclockwise of its facing
+    #[inline(always)]
+    pub fn rotation(&self, id: usize, angle: f32) -> usize {
+        let rotations = self.get(id).rotations;
+        let turns = angle.rem_euclid(2.0 * std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
+        (turns * rotations as f32).round() as usize % rotations
+    }
+
+    /// Mip level whose texels best match `footprint`, the size of a screen pixel in `UNITS`
+    #[inline(always)]
+    pub fn level(&self, id: usize, footprint: f32) -> usize {
+        let texture = &self.textures[id];
+        let texels = footprint * texture.size as f32 / UNITS as f32;
+        if texels < 2.0 {
+            0
+        } else {
+            (texels.log2() as usize).min(texture.levels - 1)
+        }
+    }
+
+    /// Nearest or bilinearly filtered texel, textures repeat outside of their bounds
+    #[inline(always)]
+    pub fn fetch(&self, sample: &Sample, bilinear: bool) -> [f32; 4] {
+        let texture = &self.textures[sample.texture];
+        let level = sample.level.min(texture.levels - 1);
+        let size = texture.size >> level;
+        let base = texture.offset + sample.frame * texture.chain_len + texture.level_offsets[level];
+        let scale = size as f32 / UNITS as f32;
+        let x = sample.position[0] * scale;
+        let y = sample.position[1] * scale;
+        let texel = |x: usize, y: usize| base + (y * size + x) * 4;
+        let wrap = |c: f32| (c.floor() as isize).rem_euclid(size as isize) as usize;
+
+        if bilinear {
+            let (x, y) = (x - 0.5, y - 0.5);
+            let (fx, fy) = (x - x.floor(), y - y.floor());
+            let (x0, y0) = (wrap(x), wrap(y));
+            let (x1, y1) = ((x0 + 1) % size, (y0 + 1) % size);
+            let weights = [
+                (texel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+                (texel(x1, y0), fx * (1.0 - fy)),
+                (texel(x0, y1), (1.0 - fx) * fy),
+                (texel(x1, y1), fx * fy),
+            ];
+            let mut out = [0.0; 4];
+            for (pos, weight) in weights {
+                for (o, t) in out.iter_mut().zip(&self.data[pos..pos + 4]) {
+                    *o += t * weight;
+                }
+            }
+            out
+        } else {
+            let pos = texel(wrap(x), wrap(y));
+            self.data[pos..pos + 4].try_into().unwrap()
+        }
+    }
+}
+
+/// Whether `frames` squares of `size` next to each other from `corner` fit in an image of
+/// `image` width and height. The mip chain halves the size down to 1x1, so it has to be a
+/// power of two
+fn check_region(
+    corner: [usize; 2],
+    size: usize,
+    frames: usize,
+    image: [usize; 2],
+) -> Result<(), String> {
+    if !size.is_power_of_two() {
+        Err(format!(
+            "is {} wide instead of a power of two, like 64 or 128, in",
+            size
+        ))
+    } else if corner[0] + size * frames > image[0] || corner[1] + size > image[1] {
+        Err("is outside".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Halves a level with a box filter. Alpha stays all or nothing so that alpha tested
+/// textures keep their shape: a texel is opaque if at least half of its sources are
+fn downsample(src: &[f32], size: usize) -> Vec<f32> {
+    let half = size / 2;
+    let mut dst = Vec::with_capacity(half * half * 4);
+    for y in 0..half {
+        for x in 0..half {
+            let sources = [
+                (2 * y * size + 2 * x) * 4,
+                (2 * y * size + 2 * x + 1) * 4,
+                ((2 * y + 1) * size + 2 * x) * 4,
+                ((2 * y + 1) * size + 2 * x + 1) * 4,
+            ];
+            let opaque: Vec<usize> = sources
+                .iter()
+                .copied()
+                .filter(|&s| src[s + 3] >= 128.0)
+                .collect();
+            let (used, alpha) = if opaque.len() >= 2 {
+                (opaque, 255.0)
+            } else {
+                (sources.to_vec(), 0.0)
+            };
+            for c in 0..3 {
+                dst.push(used.iter().map(|&s| src[s + c]).sum::<f32>() / used.len() as f32);
+            }
+            dst.push(alpha);
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn pack_different_sizes() {
+        // 512x256 image, every pixel stores its coordinates (x / 4 to fit in a byte)
+        let width = 512;
+        let pixels: Vec<u8> = (0..256)
+            .flat_map(|y| (0..width).flat_map(move |x| [(x / 4) as u8, y as u8, 0, 255]))
+            .collect();
+        let mut atlas = Atlas::default();
+        let small = atlas.add("small", &pixels, width, [0, 0], 64, 2, 4.0, 1);
+        let big = atlas.add("big", &pixels, width, [256, 0], 256, 1, 0.0, 1);
+
+        assert_eq!(atlas.id("big").unwrap(), big);
+        assert!(atlas.id("missing").is_err());
+        assert_eq!(
+            atlas.data.len(),
+            (2 * (64 * 64 + 32 * 32 + 16 * 16 + 8 * 8 + 4 * 4 + 2 * 2 + 1)
+                + (256 * 256
+                    + 128 * 128
+                    + 64 * 64
+                    + 32 * 32
+                    + 16 * 16
+                    + 8 * 8
+                    + 4 * 4
+                    + 2 * 2
+                    + 1))
+                * 4
+        );
+        assert_eq!(atlas.frame(small, 0.3), 1);
+        assert_eq!(atlas.frame(small, 0.5), 0);
+        assert_eq!(atlas.frame(big, 0.3), 0);
+
+        // Two animation frames of eight rotations each
+        let pixels = vec![0; 16 * 4];
+        let bat = atlas.add("bat", &pixels, 16, [0, 0], 1, 16, 2.0, 8);
+        assert_eq!(atlas.frame(bat, 0.6), 8);
+        assert_eq!(atlas.rotation(bat, 0.1), 0);
+        assert_eq!(atlas.rotation(bat, std::f32::consts::PI), 4);
+        assert_eq!(atlas.rotation(bat, -0.1), 0);
+        assert_eq!(atlas.rotation(bat, -std::f32::consts::FRAC_PI_4), 7);
+
+        // Second frame of the 64 texture, half way through in texture units
+        let texel = atlas.fetch(&Sample::new(small, 1, 0, [64.0, 64.0]), false);
+        assert_eq!(texel[..2], [((64 + 32) / 4) as f32, 32.0]);
+        let texel = atlas.fetch(&Sample::new(big, 0, 0, [127.0, 1.0]), false);
+        assert_eq!(texel[..2], [((256 + 254) / 4) as f32, 2.0]);
+        // Textures repeat past their edges
+        let texel = atlas.fetch(&Sample::new(big, 0, 0, [129.0, -1.0]), false);
+        assert_eq!(texel[..2], [((256 + 2) / 4) as f32, 254.0]);
+
+        // Regions are power of two squares inside their image
+        assert!(check_region([256, 0], 256, 1, [512, 256]).is_ok());
+        assert!(check_region([0, 0], 96, 1, [512, 256]).is_err());
+        assert!(check_region([0, 0], 64, 9, [512, 256]).is_err());
+        assert!(check_region([0, 200], 64, 1, [512, 256]).is_err());
+    }
+
+    #[test]
+    fn mip_levels_and_filtering() {
+        // 4x4 checkerboard of black and white texels, the last column is transparent
+        let pixels: Vec<u8> = (0..16)
+            .flat_map(|i| {
+                let c = if (i % 4 + i / 4) % 2 == 0 { 0 } else { 255 };
+                [c, c, c, if i % 4 == 3 { 0 } else { 255 }]
+            })
+            .collect();
+        let mut atlas = Atlas::default();
+        let id = atlas.add("checker", &pixels, 4, [0, 0], 4, 1, 0.0, 1);
+
+        assert_eq!(atlas.level(id, 32.0), 0);
+        assert_eq!(atlas.level(id, 64.0), 1);
+        assert_eq!(atlas.level(id, 1000.0), 2);
+
+        // Each 2x2 block averages to grey, the right half keeps two opaque texels
+        let texel = atlas.fetch(&Sample::new(id, 0, 1, [0.0, 0.0]), false);
+        assert_eq!(texel, [127.5, 127.5, 127.5, 255.0]);
+        let texel = atlas.fetch(&Sample::new(id, 0, 1, [127.0, 0.0]), false);
+        assert_eq!(texel, [127.5, 127.5, 127.5, 255.0]);
+
+        // Between a black and a white texel of the first row
+        let texel = atlas.fetch(&Sample::new(id, 0, 0, [64.0, 16.0]), true);
+        assert_eq!(texel[0], 127.5);
+    }
+}
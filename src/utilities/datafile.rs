@@ -0,0 +1,102 @@
+use std::{io::Read, path::Path, str::FromStr};
+
+use ggez::{filesystem, Context, GameError, GameResult};
+
+/// One line of a data file split into whitespace separated fields. `key=value`
+/// fields are options, the rest are positional. Lines belong to the last `[section]`
+/// header above them and `#` starts a comment.
+pub struct Entry {
+    pub section: String,
+    pub line: usize,
+    fields: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl Entry {
+    pub fn name(&self, i: usize) -> GameResult<&str> {
+        self.fields
+            .get(i)
+            .map(|f| f.as_str())
+            .ok_or_else(|| self.error(&format!("missing field {}", i + 1)))
+    }
+
+    pub fn field<T: FromStr>(&self, i: usize) -> GameResult<T> {
+        let field = self.name(i)?;
+        field
+            .parse()
+            .map_err(|_| self.error(&format!("invalid value '{}'", field)))
+    }
+
+    pub fn option<T: FromStr>(&self, key: &str) -> GameResult<Option<T>> {
+        match self.options.iter().find(|(k, _)| k == key) {
+            Some((_, value)) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(&format!("invalid value '{}' for {}", value, key))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn error(&self, msg: &str) -> GameError {
+        GameError::ResourceLoadError(format!("line {}: {}", self.line, msg))
+    }
+}
+
+pub fn parse(text: &str) -> Vec<Entry> {
+    let mut section = String::new();
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_string();
+            continue;
+        }
+        let (options, fields): (Vec<&str>, Vec<&str>) =
+            line.split_whitespace().partition(|f| f.contains('='));
+        entries.push(Entry {
+            section: section.clone(),
+            line: i + 1,
+            fields: fields.into_iter().map(String::from).collect(),
+            options: options
+                .into_iter()
+                .filter_map(|o| o.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        });
+    }
+    entries
+}
+
+pub fn read(ctx: &mut Context, path: &Path) -> GameResult<Vec<Entry>> {
+    let mut text = String::new();
+    filesystem::open(ctx, path)?.read_to_string(&mut text)?;
+    Ok(parse(&text))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn sections_fields_and_options() {
+        let entries = parse(
+            "# comment\nroot 1\n\n[walls]\n0 0 0 Brick brick upper=stone # trailing\n[floors]\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].section, "");
+        assert_eq!(entries[1].section, "walls");
+        assert_eq!(entries[1].line, 5);
+        assert_eq!(entries[1].field::<u8>(2).unwrap(), 0);
+        assert_eq!(entries[1].name(4).unwrap(), "brick");
+        assert_eq!(
+            entries[1].option::<String>("upper").unwrap(),
+            Some("stone".to_string())
+        );
+        assert!(entries[1].option::<String>("frame").unwrap().is_none());
+        assert!(entries[1].field::<u8>(3).is_err());
+        assert!(entries[1].name(5).is_err());
+    }
+}
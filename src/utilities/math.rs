@@ -2,6 +2,8 @@ use core::ops::Add;
 use core::ops::Div;
 use core::ops::Sub;
 use std::fmt::Debug;
+
+use super::vector2::Vector2;
 #[allow(dead_code)]
 #[inline(always)]
 pub fn ffmin(a: f32, b: f32) -> f32 {
@@ -11,6 +13,12 @@ pub fn ffmin(a: f32, b: f32) -> f32 {
         b
     }
 }
+/// Whether a circle overlaps the unit square of the map cell at `cell`
+pub fn circle_overlaps_cell(center: Vector2<f32>, radius: f32, cell: [usize; 2]) -> bool {
+    let dx = center.x - center.x.clamp(cell[0] as f32, cell[0] as f32 + 1.0);
+    let dy = center.y - center.y.clamp(cell[1] as f32, cell[1] as f32 + 1.0);
+    dx * dx + dy * dy < radius * radius
+}
 #[allow(dead_code)]
 pub fn linspace<T>(x0: T, xend: T, n: u16) -> Vec<T>
 where
@@ -35,3 +43,17 @@ where
 
     x
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn circle_and_cell() {
+        assert!(circle_overlaps_cell(Vector2::new(2.5, 3.5), 0.1, [2, 3]));
+        assert!(circle_overlaps_cell(Vector2::new(1.8, 3.5), 0.3, [2, 3]));
+        assert!(!circle_overlaps_cell(Vector2::new(1.8, 3.5), 0.1, [2, 3]));
+        // Corners are rounded off
+        assert!(!circle_overlaps_cell(Vector2::new(1.8, 2.8), 0.25, [2, 3]));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod datafile;
 pub mod input;
 pub mod math;
 pub mod vector2;